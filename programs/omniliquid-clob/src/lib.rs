@@ -2,6 +2,11 @@ use anchor_lang::prelude::*;
 use anchor_spl::token::{self, Token, TokenAccount, Mint, Transfer};
 use pyth_solana_receiver_sdk::price_update::PriceUpdateV2;
 
+pub mod math;
+pub mod orderbook;
+
+pub use orderbook::*;
+
 declare_id!("573mPaFytnEp1y9oKtHd1aNfwcxRc4ExYY1LthCVR4sX");
 
 // Core data structures with proper implementation
@@ -40,140 +45,381 @@ pub struct Market {
     pub mark_price_twap: u64,
     pub open_interest_long: u64,
     pub open_interest_short: u64,
-    pub cumulative_funding_long: i64,
-    pub cumulative_funding_short: i64,
+    // Cumulative funding per unit of size, in quote scaled by
+    // math::PRICE_PRECISION * math::FUNDING_PRECISION
+    pub cumulative_funding_long: i128,
+    pub cumulative_funding_short: i128,
     pub funding_interval: u64,
     pub max_leverage: u16,
+    // Funding rate per interval, in bps scaled by math::FUNDING_PRECISION,
+    // accrued continuously into the cumulative indexes
+    pub funding_rate: i64,
+    pub last_funding_accrual: u64,
     
-    // User position data (for perpetuals)
-    pub user_positions: Vec<(Pubkey, Position)>,
+    // Mark and oracle TWAPs over the funding interval, and the latest mark
+    // observation (the latest oracle observation is `last_oracle_price`)
+    pub oracle_price_twap: u64,
+    pub last_mark_price: u64,
+    pub last_twap_timestamp: u64,
+    // Period, in seconds, over which a premium is paid off, and the largest
+    // funding rate charged per interval in bps
+    pub funding_period: u64,
+    pub max_funding_rate: u16,
     
     // Oracle feed ID for Pyth integration
     pub oracle_feed_id: [u8; 32],
     pub max_oracle_age: u64,
+    // Widest confidence interval accepted, in bps of the price
+    pub max_oracle_confidence: u16,
+    // Fallbacks when the primary feed fails: a secondary Pyth feed (all zeros
+    // when unset), then the book's mark TWAP if enabled. Each must stay within
+    // `max_oracle_deviation` bps of the source it checks against.
+    pub secondary_oracle_feed_id: [u8; 32],
+    pub book_twap_fallback: bool,
+    pub max_oracle_deviation: u16,
+    
+    // Circuit breakers, all in bps: how far limit prices may sit from the
+    // oracle, how far fills may move from the last trade before the current
+    // slot, and how far the mark may stray from the oracle before the market
+    // pauses itself. Zero disables the last two.
+    pub price_band: u16,
+    pub max_slot_price_move: u16,
+    pub max_mark_deviation: u16,
+    pub last_trade_price: u64,
+    pub last_trade_slot: u64,
+    pub slot_open_price: u64,
+    
+    // Fee accounting
+    pub fee_vault: Pubkey,
+    pub accrued_fees: u64,
+    // Flat fee, in quote units, paid to the keeper executing a trigger order
+    pub trigger_order_fee: u64,
+    
+    // Asset parameters cached from the registry
+    pub maintenance_margin_ratio: u16,
+    pub liquidation_fee: u16,
+    pub asset_max_leverage: u16,
+    pub funding_rate_multiplier: u16,
+    pub asset_active: bool,
+    
+    // Largest share of a position, in bps, closed by one liquidation
+    pub liquidation_close_factor: u16,
+    // Furthest from the oracle price, in bps, a liquidation may fill on the book
+    pub liquidation_max_slippage: u16,
+    
+    // Insurance fund covering bad debt left by liquidations
+    pub insurance_fund: Pubkey,
+    pub insurance_fund_balance: u64,
+    // Share of perp protocol fees and liquidation fees, in bps, paid into the insurance fund
+    pub insurance_fee_share: u16,
+    // Bad debt per unit of size charged to each side once the insurance fund is empty
+    pub socialized_loss_index_long: u64,
+    pub socialized_loss_index_short: u64,
 }
 
+// Liquidation parameters new markets start with
+pub const DEFAULT_LIQUIDATION_CLOSE_FACTOR: u16 = 5000;
+pub const DEFAULT_LIQUIDATION_MAX_SLIPPAGE: u16 = 500;
+
+// Insurance fund share of fees new markets start with
+pub const DEFAULT_INSURANCE_FEE_SHARE: u16 = 2000;
+
+// Funding parameters new markets start with: the premium is paid off over
+// this many funding intervals, and the rate is capped per interval in bps
+pub const DEFAULT_FUNDING_PERIOD_INTERVALS: u64 = 24;
+pub const DEFAULT_MAX_FUNDING_RATE: u16 = 100;
+
+// Widest oracle confidence interval new markets accept, in bps of the price
+pub const DEFAULT_MAX_ORACLE_CONFIDENCE: u16 = 200;
+
+// Largest disagreement between price sources new markets accept, in bps
+pub const DEFAULT_MAX_ORACLE_DEVIATION: u16 = 500;
+
+// Circuit breaker parameters new markets start with, in bps
+pub const DEFAULT_PRICE_BAND: u16 = 5000;
+pub const DEFAULT_MAX_SLOT_PRICE_MOVE: u16 = 1000;
+pub const DEFAULT_MAX_MARK_DEVIATION: u16 = 2000;
+
+// Keeper fee for executing a trigger order new markets start with, in quote units
+pub const DEFAULT_TRIGGER_ORDER_FEE: u64 = 100_000;
+
 impl Market {
     pub const SIZE: usize = 32 + 32 + 32 + 32 + 32 + 1 + 32 + 
                            8 + 8 + 2 + 2 + 
                            8 + 8 + 1 + 
                            64 + 32 + 32 + 1 + 1 + 
-                           8 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 2 +
-                           4 + (50 * (32 + 8 + 8 + 8 + 2 + 8 + 8)) +
-                           32 + 8; // Added oracle_feed_id and max_oracle_age
+                           8 + 8 + 8 + 8 + 8 + 8 + 16 + 16 + 8 + 2 +
+                           32 + 8 + // Added oracle_feed_id and max_oracle_age
+                           32 + 8 + // Added fee_vault and accrued_fees
+                           2 + 2 + 2 + 2 + 1 + // Added cached asset parameters
+                           2 + 2 + // Added liquidation close factor and max slippage
+                           32 + 8 + 2 + 8 + 8 + // Added insurance fund and socialized loss indexes
+                           8 + 8 + // Added funding rate and last funding accrual
+                           8 + 8 + 8 + 8 + 2 + // Added oracle TWAP, mark observation and funding period and cap
+                           2 + // Added max_oracle_confidence
+                           32 + 1 + 2 + // Added oracle fallbacks and max deviation
+                           2 + 2 + 2 + 8 + 8 + 8 + // Added circuit breaker parameters and state
+                           8; // Added trigger_order_fee
 
-    pub fn get_position(&self, user: &Pubkey) -> Option<(usize, &Position)> {
-        self.user_positions
-            .iter()
-            .enumerate()
-            .find(|(_, (owner, _))| owner == user)
-            .map(|(idx, (_, position))| (idx, position))
+    // Start tracking price moves for `slot`, anchored at the last trade before it
+    pub fn start_slot(&mut self, slot: u64) {
+        if slot != self.last_trade_slot {
+            self.slot_open_price = self.last_trade_price;
+            self.last_trade_slot = slot;
+        }
     }
 
-    pub fn get_position_mut(&mut self, user: &Pubkey) -> Option<(usize, &mut Position)> {
-        self.user_positions
-            .iter_mut()
-            .enumerate()
-            .find(|(_, (owner, _))| owner == user)
-            .map(|(idx, (_, position))| (idx, position))
+    // Furthest price a taker on `side` may fill at in the current slot
+    pub fn slot_price_limit(&self, side: Side) -> Result<Option<u64>> {
+        if self.max_slot_price_move == 0 || self.slot_open_price == 0 {
+            return Ok(None);
+        }
+        
+        let max_move = math::bps_of(self.slot_open_price, self.max_slot_price_move)?;
+        Ok(Some(match side {
+            Side::Bid => self.slot_open_price.saturating_add(max_move),
+            Side::Ask => self.slot_open_price.saturating_sub(max_move),
+        }))
     }
-}
-#[account]
-pub struct Orderbook {
-    pub market: Pubkey,
-    pub bids: Vec<(u64, Vec<Order>)>, // Price level -> Orders at that price
-    pub asks: Vec<(u64, Vec<Order>)>, // Price level -> Orders at that price
-}
 
-impl Orderbook {
-    pub const SIZE: usize = 32 + 
-                          4 + (50 * (8 + 4 + (20 * (8 + 8 + 32 + 1 + 8 + 8 + 1 + 8 + 1 + 1)))) +
-                          4 + (50 * (8 + 4 + (20 * (8 + 8 + 32 + 1 + 8 + 8 + 1 + 8 + 1 + 1))));
+    // Furthest price a taker on `side` with an optional `limit` may fill at in
+    // the current slot: the limit pulled in to the slot's allowed move, or the
+    // move itself without one. The move is rounded onto the tick grid towards
+    // the slot open, so what's left of a clamped order can rest at the result
+    // without crossing the book.
+    pub fn slot_limited_price(&self, side: Side, limit: Option<u64>) -> Result<Option<u64>> {
+        let Some(slot_limit) = self.slot_price_limit(side)? else {
+            return Ok(limit);
+        };
+        
+        let tick_size = std::cmp::max(self.tick_size, 1);
+        Ok(Some(match side {
+            Side::Bid => {
+                let cap = slot_limit - slot_limit % tick_size;
+                limit.map_or(cap, |limit| std::cmp::min(limit, cap))
+            },
+            Side::Ask => {
+                let cap = slot_limit
+                    .div_ceil(tick_size)
+                    .checked_mul(tick_size)
+                    .ok_or(ErrorCode::MathOverflow)?;
+                limit.map_or(cap, |limit| std::cmp::max(limit, cap))
+            },
+        }))
+    }
 
-    // Find orders for a specific user
-    pub fn find_orders_for_user(&self, user: &Pubkey) -> Vec<(Side, u64, usize, usize)> {
-        let mut orders = Vec::new();
+    // Limit prices must sit within the market's band around the oracle price
+    pub fn check_price_band(&self, price: u64, oracle_price: u64) -> Result<()> {
+        let band = math::bps_of(oracle_price, self.price_band)?;
+        require!(
+            price >= oracle_price.saturating_sub(band) && price <= oracle_price.saturating_add(band),
+            ErrorCode::PriceOutOfRange
+        );
         
-        // Check bids
-        for (price_idx, (price, price_orders)) in self.bids.iter().enumerate() {
-            for (order_idx, order) in price_orders.iter().enumerate() {
-                if &order.user == user {
-                    orders.push((Side::Bid, *price, price_idx, order_idx));
-                }
-            }
+        Ok(())
+    }
+
+    // Pause the market when the mark TWAP strays too far from the oracle
+    // TWAP. A single fill only moves the TWAPs by the time it stands, so one
+    // off-market trade can't trip it. Returns whether the breaker tripped.
+    pub fn check_mark_deviation(&mut self) -> Result<bool> {
+        if self.max_mark_deviation == 0 || self.status != MarketStatus::Active || self.oracle_price_twap == 0 {
+            return Ok(false);
+        }
         
+        if math::deviation_bps(self.mark_price_twap, self.oracle_price_twap)? > self.max_mark_deviation as u64 {
+            self.status = MarketStatus::Paused;
+            return Ok(true);
         }
         
-        // Check asks
-        for (price_idx, (price, price_orders)) in self.asks.iter().enumerate() {
-            for (order_idx, order) in price_orders.iter().enumerate() {
-                if &order.user == user {
-                    orders.push((Side::Ask, *price, price_idx, order_idx));
-                }
-            }
+        Ok(false)
+    }
+
+    // Fold the latest mark and oracle observations into the TWAPs. Each
+    // observation is weighted by how long it stood, so a price only counts
+    // once time has passed after it was observed.
+    pub fn update_twaps(&mut self, mark_price: u64, oracle_price: u64, timestamp: u64) -> Result<()> {
+        if self.mark_price_twap == 0 || self.oracle_price_twap == 0 {
+            self.mark_price_twap = mark_price;
+            self.oracle_price_twap = oracle_price;
+            self.last_twap_timestamp = timestamp;
+        } else if timestamp > self.last_twap_timestamp {
+            let elapsed = timestamp - self.last_twap_timestamp;
+            let window = std::cmp::max(self.funding_interval, 1);
+            self.mark_price_twap = math::twap(self.mark_price_twap, self.last_mark_price, elapsed, window)?;
+            self.oracle_price_twap = math::twap(self.oracle_price_twap, self.last_oracle_price, elapsed, window)?;
+            self.last_twap_timestamp = timestamp;
         }
         
-        orders
+        self.last_mark_price = mark_price;
+        self.last_oracle_price = oracle_price;
+        
+        Ok(())
     }
 
-    // Get the best bid price
-    pub fn best_bid_price(&self) -> Option<u64> {
-        self.bids
-            .first()
-            .map(|(price, _)| *price)
+    // Price funding accrues at: the oracle TWAP, or the latest oracle
+    // observation before the TWAP has started
+    pub fn funding_price(&self) -> u64 {
+        if self.oracle_price_twap > 0 {
+            self.oracle_price_twap
+        } else {
+            self.last_oracle_price
+        }
     }
-    
-    // Get the best ask price
-    pub fn best_ask_price(&self) -> Option<u64> {
-        self.asks
-            .first()
-            .map(|(price, _)| *price)
+
+    // Cumulative long funding index at `timestamp`, accruing the current rate
+    // pro rata over the time since the last accrual at the funding price
+    pub fn cumulative_funding_at(&self, timestamp: u64) -> Result<i128> {
+        let elapsed = timestamp.saturating_sub(self.last_funding_accrual);
+        if elapsed == 0 || self.funding_interval == 0 {
+            return Ok(self.cumulative_funding_long);
+        }
+        
+        let accrued = (self.funding_rate as i128)
+            .checked_mul(elapsed as i128)
+            .and_then(|rate| rate.checked_mul(self.funding_price() as i128))
+            .ok_or(ErrorCode::MathOverflow)?
+            / (self.funding_interval as i128 * math::BPS_DENOMINATOR as i128);
+        self.cumulative_funding_long
+            .checked_add(accrued)
+            .ok_or(ErrorCode::MathOverflow.into())
     }
 
-    // Calculate mid-price
-    pub fn mid_price(&self) -> Option<u64> {
-        match (self.best_bid_price(), self.best_ask_price()) {
-            (Some(bid), Some(ask)) => Some((bid + ask) / 2),
-            (Some(bid), None) => Some(bid),
-            (None, Some(ask)) => Some(ask),
-            (None, None) => None,
+    // Bring the cumulative funding indexes up to `timestamp`
+    pub fn accrue_funding(&mut self, timestamp: u64) -> Result<()> {
+        if timestamp <= self.last_funding_accrual {
+            return Ok(());
         }
+        
+        let cumulative_funding_long = self.cumulative_funding_at(timestamp)?;
+        let accrued = cumulative_funding_long
+            .checked_sub(self.cumulative_funding_long)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.cumulative_funding_short = self.cumulative_funding_short
+            .checked_sub(accrued)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.cumulative_funding_long = cumulative_funding_long;
+        self.last_funding_accrual = timestamp;
+        
+        Ok(())
     }
-    
-    // Place a bid order at a specific price level
-    pub fn place_bid(&mut self, price: u64, order: Order) {
-        let position = self.bids
-            .iter()
-            .position(|(p, _)| *p == price);
-            
-        if let Some(idx) = position {
-            // Add to existing price level
-            self.bids[idx].1.push(order);
-        } else {
-            // Create new price level
-            self.bids.push((price, vec![order]));
-            // Sort bids in descending order (highest first)
-            self.bids.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+    // Socialized loss index charged to positions on `side`
+    pub fn socialized_loss_index(&self, side: Side) -> u64 {
+        match side {
+            Side::Bid => self.socialized_loss_index_long,
+            Side::Ask => self.socialized_loss_index_short,
         }
     }
-    
-    // Place an ask order at a specific price level
-    pub fn place_ask(&mut self, price: u64, order: Order) {
-        let position = self.asks
+
+    // Spread bad debt left by a bankrupt position on `bankrupt_side` over the
+    // open interest on the other side, which took the matching gains.
+    // Returns the amount charged, which is zero when that side has no open interest.
+    pub fn socialize_loss(&mut self, bankrupt_side: Side, amount: u64) -> Result<u64> {
+        let (open_interest, index) = match bankrupt_side {
+            Side::Bid => (self.open_interest_short, &mut self.socialized_loss_index_short),
+            Side::Ask => (self.open_interest_long, &mut self.socialized_loss_index_long),
+        };
+        
+        if open_interest == 0 {
+            return Ok(0);
+        }
+        
+        // Loss per unit of size, rounded up so the whole amount is recovered
+        let index_delta = (amount as u128)
+            .checked_mul(math::PRICE_PRECISION as u128)
+            .ok_or(ErrorCode::MathOverflow)?
+            .div_ceil(open_interest as u128);
+        *index = index
+            .checked_add(math::to_u64(index_delta as i128)?)
+            .ok_or(ErrorCode::MathOverflow)?;
+        
+        Ok(amount)
+    }
+
+    // Refresh the cached asset parameters from the registry
+    pub fn sync_asset_params(&mut self, registry: &omniliquid_registry::Registry) -> Result<()> {
+        let (_, asset) = registry.supported_assets
             .iter()
-            .position(|(p, _)| *p == price);
-            
-        if let Some(idx) = position {
-            // Add to existing price level
-            self.asks[idx].1.push(order);
+            .find(|(id, _)| *id == self.asset_id)
+            .ok_or(ErrorCode::AssetNotAvailable)?;
+        
+        self.maintenance_margin_ratio = asset.maintenance_margin_ratio;
+        self.liquidation_fee = asset.liquidation_fee;
+        self.asset_max_leverage = asset.max_leverage;
+        self.funding_rate_multiplier = asset.funding_rate_multiplier;
+        self.asset_active = asset.active;
+        
+        Ok(())
+    }
+
+    // Apply a perpetual fill to a user's position, opening, increasing,
+    // reducing or flipping it as needed. Returns the PnL realized by the fill.
+    pub fn apply_fill(&mut self, position: &mut Position, side: Side, price: u64, size: u64, timestamp: u64) -> Result<i64> {
+        let mut pnl = 0;
+        
+        // Settle funding and socialized losses accrued on the old size before changing it
+        self.accrue_funding(timestamp)?;
+        position.settle_funding(self.cumulative_funding_long)?;
+        position.settle_socialized_loss(self.socialized_loss_index(position.side))?;
+        
+        // Take the old exposure out of open interest
+        let open_interest = match position.side {
+            Side::Bid => &mut self.open_interest_long,
+            Side::Ask => &mut self.open_interest_short,
+        };
+        *open_interest = open_interest.checked_sub(position.size).ok_or(ErrorCode::MathOverflow)?;
+        
+        if position.size == 0 {
+            // New position
+            position.side = side;
+            position.size = size;
+            position.entry_price = price;
+        } else if position.side == side {
+            // Add to existing position
+            let new_size = position.size.checked_add(size).ok_or(ErrorCode::MathOverflow)?;
+            let total_cost = (position.entry_price as i128)
+                .checked_mul(position.size as i128)
+                .and_then(|cost| cost.checked_add(price as i128 * size as i128))
+                .ok_or(ErrorCode::MathOverflow)?;
+            position.entry_price = math::to_u64(total_cost / new_size as i128)?;
+            position.size = new_size;
         } else {
-            // Create new price level
-            self.asks.push((price, vec![order]));
-            // Sort asks in ascending order (lowest first)
-            self.asks.sort_by(|(a, _), (b, _)| a.cmp(b));
+            // Book PnL on the closed size against the entry price
+            let closed_size = std::cmp::min(size, position.size);
+            pnl = position.pnl_for_size(price, closed_size)?;
+            position.realized_pnl = position.realized_pnl.checked_add(pnl).ok_or(ErrorCode::MathOverflow)?;
+            
+            if size < position.size {
+                // Reduce position
+                position.size -= size;
+            } else {
+                // Close or flip position
+                position.side = side;
+                position.size = size - position.size;
+                position.entry_price = price;
+            }
         }
+        
+        // Add the new exposure back to open interest
+        let open_interest = match position.side {
+            Side::Bid => &mut self.open_interest_long,
+            Side::Ask => &mut self.open_interest_short,
+        };
+        *open_interest = open_interest.checked_add(position.size).ok_or(ErrorCode::MathOverflow)?;
+        
+        // Update position metadata
+        position.last_socialized_loss_index = self.socialized_loss_index(position.side);
+        position.last_updated_timestamp = timestamp;
+        position.update_liquidation_price(self.maintenance_margin_ratio)?;
+        
+        Ok(pnl)
     }
-} 
+}
+// Time in force of resting orders
+pub const TIME_IN_FORCE_GOOD_TILL_CANCEL: u8 = 0;
+pub const TIME_IN_FORCE_GOOD_TILL_TIME: u8 = 1;
+
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
 pub struct Order {
     pub id: u64,
@@ -185,6 +431,8 @@ pub struct Order {
     pub remaining_size: u64,
     pub time_in_force: u8,
     pub timestamp: u64,
+    // Unix timestamp after which the order can't be filled, zero for none
+    pub expiry: u64,
     pub reduce_only: bool,
     pub post_only: bool,
 }
@@ -198,6 +446,7 @@ impl Order {
         price: u64,
         size: u64,
         time_in_force: u8,
+        expiry: u64,
         reduce_only: bool,
         post_only: bool,
     ) -> Self {
@@ -212,6 +461,7 @@ impl Order {
             remaining_size: size,
             time_in_force,
             timestamp,
+            expiry,
             reduce_only,
             post_only,
         }
@@ -222,99 +472,354 @@ impl Order {
     }
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+// Perpetual position of one user in one market, stored at the PDA
+// [b"position", market, owner]
+#[account]
 pub struct Position {
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub bump: u8,
     pub side: Side,
     pub size: u64,
-    pub margin: u64,
     pub entry_price: u64,
     pub leverage: u16,
-    pub last_funding_index: i64,
+    pub last_funding_index: i128,
+    pub last_socialized_loss_index: u64,
+    // PnL and funding not yet settled into the owner's margin account
     pub realized_pnl: i64,
     pub liquidation_price: u64,
     pub last_updated_timestamp: u64,
 }
 
 impl Position {
-    pub fn new(side: Side, margin: u64) -> Self {
-        Self {
-            side,
-            size: 0,
-            margin,
-            entry_price: 0,
-            leverage: 1,
-            last_funding_index: 0,
-            realized_pnl: 0,
-            liquidation_price: 0,
-            last_updated_timestamp: Clock::get().unwrap().unix_timestamp as u64,
-        }
-    }
+    pub const SIZE: usize = 32 + 32 + 1 + 1 + 8 + 8 + 2 + 16 + 8 + 8 + 8 + 8;
 
     pub fn is_empty(&self) -> bool {
         self.size == 0
     }
 
     // Calculate the position's unrealized PnL at a given price
-    pub fn calculate_unrealized_pnl(&self, current_price: u64) -> i64 {
-        if self.size == 0 {
-            return 0;
-        }
+    pub fn calculate_unrealized_pnl(&self, current_price: u64) -> Result<i64> {
+        self.pnl_for_size(current_price, self.size)
+    }
 
-        match self.side {
-            Side::Bid => ((current_price as i128 - self.entry_price as i128) * self.size as i128 / 1_000_000) as i64,
-            Side::Ask => ((self.entry_price as i128 - current_price as i128) * self.size as i128 / 1_000_000) as i64,
+    // Calculate the PnL of closing `size` of the position at a given price
+    pub fn pnl_for_size(&self, current_price: u64, size: u64) -> Result<i64> {
+        if size == 0 {
+            return Ok(0);
         }
+
+        math::pnl(self.side, self.entry_price, current_price, size)
     }
 
-    // Calculate position equity (margin + unrealized PnL)
-    pub fn equity(&self, current_price: u64) -> u64 {
-        let unrealized_pnl = self.calculate_unrealized_pnl(current_price);
-        if unrealized_pnl < 0 && self.margin < unrealized_pnl.unsigned_abs() {
-            0
-        } else {
-            self.margin + unrealized_pnl.max(-(self.margin as i64)) as u64
+    // Funding accrued since the position last settled, from the change in the
+    // market's cumulative long funding index. Longs pay when the index rises.
+    pub fn pending_funding(&self, cumulative_funding_long: i128) -> Result<i64> {
+        if self.size == 0 {
+            return Ok(0);
         }
+
+        let index_delta = cumulative_funding_long
+            .checked_sub(self.last_funding_index)
+            .ok_or(ErrorCode::MathOverflow)?;
+        math::funding_payment(self.side, self.size, index_delta)
+    }
+
+    // Move pending funding into unsettled PnL and advance the funding index
+    pub fn settle_funding(&mut self, cumulative_funding_long: i128) -> Result<i64> {
+        let funding = self.pending_funding(cumulative_funding_long)?;
+        self.realized_pnl = self.realized_pnl.checked_add(funding).ok_or(ErrorCode::MathOverflow)?;
+        self.last_funding_index = cumulative_funding_long;
+        
+        Ok(funding)
+    }
+
+    // Share of socialized bad debt charged to the position since it last settled
+    pub fn pending_socialized_loss(&self, socialized_loss_index: u64) -> Result<u64> {
+        let index_delta = socialized_loss_index.saturating_sub(self.last_socialized_loss_index);
+        math::notional(self.size, index_delta)
+    }
+
+    // Move pending socialized losses into unsettled PnL and advance the index
+    pub fn settle_socialized_loss(&mut self, socialized_loss_index: u64) -> Result<u64> {
+        let loss = self.pending_socialized_loss(socialized_loss_index)?;
+        self.realized_pnl = self.realized_pnl
+            .checked_sub(math::to_i64(loss as i128)?)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.last_socialized_loss_index = socialized_loss_index;
+        
+        Ok(loss)
     }
 
     // Calculate the position's notional value
-    pub fn notional_value(&self, current_price: u64) -> u64 {
-        self.size * current_price / 1_000_000
+    pub fn notional_value(&self, current_price: u64) -> Result<u64> {
+        math::notional(self.size, current_price)
+    }
+
+    // Auto-deleveraging rank of the position at a given price
+    pub fn adl_score(&self, current_price: u64) -> Result<u64> {
+        let entry_notional = math::notional(self.size, self.entry_price)?;
+        Ok(math::adl_score(
+            self.calculate_unrealized_pnl(current_price)?,
+            entry_notional,
+            self.leverage,
+        ))
     }
 
     // Update the position's liquidation price
-    pub fn update_liquidation_price(&mut self, maintenance_margin_ratio: u16) {
+    pub fn update_liquidation_price(&mut self, maintenance_margin_ratio: u16) -> Result<()> {
         if self.size == 0 {
             self.liquidation_price = 0;
-            return;
+            return Ok(());
         }
 
-        let maintenance_ratio = maintenance_margin_ratio as f64 / 10000.0;
+        self.liquidation_price = math::liquidation_price(
+            self.side,
+            self.entry_price,
+            self.leverage,
+            maintenance_margin_ratio,
+        )?;
+        
+        Ok(())
+    }
+} 
 
-        match self.side {
-            Side::Bid => {
-                let ratio = 1.0 - (1.0 / self.leverage as f64) + maintenance_ratio;
-                self.liquidation_price = (self.entry_price as f64 * ratio) as u64;
-            },
-            Side::Ask => {
-                let ratio = 1.0 + (1.0 / self.leverage as f64) - maintenance_ratio;
-                self.liquidation_price = (self.entry_price as f64 * ratio) as u64;
-            }
+// Maximum number of markets a margin account can hold positions in
+pub const MAX_MARGIN_POSITIONS: usize = 8;
+
+// Cross-margin account holding a user's collateral for all perpetual markets,
+// stored at the PDA [b"margin_account", owner]. The collateral itself sits in
+// the shared collateral vault for `collateral_mint`.
+#[account]
+pub struct MarginAccount {
+    pub owner: Pubkey,
+    pub bump: u8,
+    pub collateral_mint: Pubkey,
+    pub collateral: u64,
+    pub positions: Vec<Pubkey>,
+}
+
+impl MarginAccount {
+    pub const SIZE: usize = 32 + 1 + 32 + 8 + 4 + (MAX_MARGIN_POSITIONS * 32);
+
+    // Move a position's unsettled PnL into collateral, returning any loss the
+    // collateral could not cover. Callers must reject the trade or cover it.
+    #[must_use = "a loss the collateral could not cover must be rejected or covered"]
+    pub fn settle_pnl(&mut self, position: &mut Position) -> Result<u64> {
+        let pnl = position.realized_pnl;
+        position.realized_pnl = 0;
+        
+        let shortfall = math::to_u64(-std::cmp::min(self.collateral as i128 + pnl as i128, 0))?;
+        self.collateral = math::equity(self.collateral, pnl)?;
+        Ok(shortfall)
+    }
+}
+
+// Spot tokens held in a market's vaults for one user to claim, stored at the
+// PDA [b"claimable_balances", market, owner]. Makers are credited here as
+// their orders fill or expire, so a taker never has to pass the makers'
+// token accounts and unclaimed balances take no room in the orderbook.
+#[account]
+pub struct ClaimableBalances {
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub bump: u8,
+    pub base_claimable: u64,
+    pub quote_claimable: u64,
+}
+
+impl ClaimableBalances {
+    pub const SIZE: usize = 32 + 32 + 1 + 8 + 8;
+
+    pub fn credit(&mut self, base_amount: u64, quote_amount: u64) -> Result<()> {
+        self.base_claimable = self.base_claimable.checked_add(base_amount).ok_or(ErrorCode::MathOverflow)?;
+        self.quote_claimable = self.quote_claimable.checked_add(quote_amount).ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    // Zero the balances and return the base and quote amounts
+    pub fn take(&mut self) -> (u64, u64) {
+        let amounts = (self.base_claimable, self.quote_claimable);
+        self.base_claimable = 0;
+        self.quote_claimable = 0;
+        amounts
+    }
+}
+
+// Maximum number of trigger orders a user can have open in one market
+pub const MAX_TRIGGER_ORDERS: usize = 8;
+
+// Stop and take-profit orders against a user's position in one market,
+// stored at the PDA [b"trigger_orders", market, owner]. Keepers execute them
+// once the oracle price reaches their trigger.
+#[account]
+pub struct TriggerOrders {
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub bump: u8,
+    pub next_id: u64,
+    pub orders: Vec<TriggerOrder>,
+}
+
+impl TriggerOrders {
+    pub const SIZE: usize = 32 + 32 + 1 + 8 + 4 + (MAX_TRIGGER_ORDERS * TriggerOrder::SIZE);
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug)]
+pub struct TriggerOrder {
+    pub id: u64,
+    pub kind: TriggerOrderKind,
+    // Side of the reduce-only order placed when triggered
+    pub side: Side,
+    pub trigger_price: u64,
+    // Worst fill price, required for stop-limit orders and optional (zero) otherwise
+    pub limit_price: u64,
+    pub size: u64,
+    pub timestamp: u64,
+}
+
+impl TriggerOrder {
+    pub const SIZE: usize = 8 + 1 + 1 + 8 + 8 + 8 + 8;
+
+    // Stops fire when the price moves against the position being closed,
+    // take-profits when it moves in its favour
+    pub fn is_triggered(&self, oracle_price: u64) -> bool {
+        match (self.kind, self.side) {
+            (TriggerOrderKind::TakeProfit, Side::Ask) => oracle_price >= self.trigger_price,
+            (TriggerOrderKind::TakeProfit, Side::Bid) => oracle_price <= self.trigger_price,
+            (_, Side::Ask) => oracle_price <= self.trigger_price,
+            (_, Side::Bid) => oracle_price >= self.trigger_price,
         }
     }
+}
 
-    // Check if the position is liquidatable at the current price
-    pub fn is_liquidatable(&self, current_price: u64, maintenance_margin_ratio: u16) -> bool {
-        if self.size == 0 {
-            return false;
+// Maximum number of positions ranked for auto-deleveraging on each side of a market
+pub const MAX_ADL_QUEUE_ENTRIES: usize = 16;
+
+// Highest ranked positions on each side of a market for auto-deleveraging,
+// best first, stored at the PDA [b"adl_queue", market]. Anyone can push
+// positions into it with update_adl_queue, so a position can only stay out
+// while everything queued outranks it.
+#[account]
+pub struct AdlQueue {
+    pub market: Pubkey,
+    pub bump: u8,
+    pub longs: Vec<AdlQueueEntry>,
+    pub shorts: Vec<AdlQueueEntry>,
+}
+
+impl AdlQueue {
+    pub const SIZE: usize = 32 + 1 + 2 * (4 + MAX_ADL_QUEUE_ENTRIES * AdlQueueEntry::SIZE);
+
+    pub fn entries(&self, side: Side) -> &Vec<AdlQueueEntry> {
+        match side {
+            Side::Bid => &self.longs,
+            Side::Ask => &self.shorts,
+        }
+    }
+
+    pub fn entries_mut(&mut self, side: Side) -> &mut Vec<AdlQueueEntry> {
+        match side {
+            Side::Bid => &mut self.longs,
+            Side::Ask => &mut self.shorts,
         }
+    }
+
+    // Take a position out of the queue on both sides
+    pub fn remove(&mut self, position: &Pubkey) {
+        self.longs.retain(|entry| entry.position != *position);
+        self.shorts.retain(|entry| entry.position != *position);
+    }
 
-        let position_value = self.notional_value(current_price);
-        let required_margin = position_value * maintenance_margin_ratio as u64 / 10000;
+    // Record a position's current score on `side`, replacing any older entry.
+    // Equal scores keep their queue order, and whatever falls past the end of
+    // the queue is dropped. Positions that are not in profit aren't queued.
+    pub fn update(&mut self, position: Pubkey, side: Side, score: u64) {
+        self.remove(&position);
+        if score == 0 {
+            return;
+        }
         
-        let equity = self.equity(current_price);
-        equity < required_margin
+        let entries = self.entries_mut(side);
+        let index = entries.partition_point(|entry| entry.score >= score);
+        entries.insert(index, AdlQueueEntry { position, score });
+        entries.truncate(MAX_ADL_QUEUE_ENTRIES);
     }
-} 
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AdlQueueEntry {
+    pub position: Pubkey,
+    // Score when the entry was last updated
+    pub score: u64,
+}
+
+impl AdlQueueEntry {
+    pub const SIZE: usize = 32 + 8;
+}
+
+// Equity and margin requirements aggregated over a margin account's positions
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MarginSummary {
+    // Collateral plus unsettled PnL, pending funding and unrealized PnL
+    pub equity: i128,
+    // Equity with positive unrealized PnL left out, which cannot be withdrawn
+    pub withdrawable_equity: i128,
+    pub initial_margin: u128,
+    pub maintenance_margin: u128,
+}
+
+impl MarginSummary {
+    pub fn new(collateral: u64) -> Self {
+        Self {
+            equity: collateral as i128,
+            withdrawable_equity: collateral as i128,
+            ..Default::default()
+        }
+    }
+
+    // Add a position in `market` valued at `price`, with funding accrued up to
+    // `timestamp`. Its initial requirement covers the exposure it could reach
+    // if the owner's resting orders in the market fill; maintenance only
+    // covers the position itself.
+    pub fn add_position(
+        &mut self,
+        position: &Position,
+        market: &Market,
+        price: u64,
+        open_orders: &OpenOrderTotals,
+        timestamp: u64,
+    ) -> Result<()> {
+        self.add_equity(position, market, price, timestamp)?;
+        
+        let max_exposure = open_orders.worst_case_exposure(position.side, position.size, price)?;
+        self.initial_margin += max_exposure / std::cmp::max(position.leverage, 1) as u128;
+        
+        let notional = position.notional_value(price)?;
+        self.maintenance_margin += math::bps_of(notional, market.maintenance_margin_ratio)? as u128;
+        
+        Ok(())
+    }
+
+    // Add a position's unsettled PnL, pending funding and socialized losses,
+    // and its unrealized PnL at `price`, without its margin requirement
+    pub fn add_equity(&mut self, position: &Position, market: &Market, price: u64, timestamp: u64) -> Result<()> {
+        let unrealized_pnl = position.calculate_unrealized_pnl(price)? as i128;
+        let settled = position.realized_pnl as i128
+            + position.pending_funding(market.cumulative_funding_at(timestamp)?)? as i128
+            - position.pending_socialized_loss(market.socialized_loss_index(position.side))? as i128;
+        
+        self.equity += settled + unrealized_pnl;
+        self.withdrawable_equity += settled + std::cmp::min(unrealized_pnl, 0);
+        
+        Ok(())
+    }
+
+    pub fn is_liquidatable(&self) -> bool {
+        self.equity < self.maintenance_margin as i128
+    }
+}
+
 // Enums
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Side {
@@ -328,6 +833,20 @@ pub enum OrderType {
     ImmediateOrCancel,
     PostOnly,
     Market,
+    // Limit order that rests until the given unix timestamp
+    GoodTillTime { expiry: u64 },
+    // Fills in full immediately or not at all
+    FillOrKill,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TriggerOrderKind {
+    // Takes liquidity up to the limit price, if any, once triggered
+    StopMarket,
+    // Takes liquidity up to the limit price and rests the remainder
+    StopLimit,
+    // Takes liquidity up to the limit price, if any, once in profit
+    TakeProfit,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
@@ -338,6 +857,21 @@ pub enum SelfTradeBehavior {
     CancelBoth,
 }
 
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OracleSource {
+    Primary,
+    Secondary,
+    BookTwap,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LiquidationMode {
+    // Close against resting orders within the market's slippage limit
+    Orderbook,
+    // Hand the position to the liquidator at a discount to the oracle price
+    Transfer,
+}
+
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
 pub enum MarketStatus {
     Active,
@@ -370,68 +904,233 @@ pub struct MarketStatusChanged {
 }
 
 #[event]
-pub struct OrderMatched {
+pub struct CircuitBreakerTripped {
     pub market: Pubkey,
-    pub order_id: u64,
-    pub maker_order_id: u64,
-    pub client_id: u64,
-    pub maker_client_id: u64,
-    pub user: Pubkey,
-    pub maker: Pubkey,
-    pub side: Side,
-    pub price: u64,
-    pub size: u64,
-    pub quote_amount: u64,
-    pub taker_fee: u64,
-    pub maker_rebate: u64,
-    pub remaining_size: u64,
+    pub mark_price: u64,
+    pub oracle_price: u64,
+    pub max_mark_deviation: u16,
     pub timestamp: u64,
 }
 
 #[event]
-pub struct BidOrderAdded {
+pub struct CircuitBreakerParamsUpdated {
     pub market: Pubkey,
-    pub order_id: u64,
-    pub client_id: u64,
-    pub user: Pubkey,
-    pub price: u64,
-    pub size: u64,
-    pub reduce_only: bool,
-    pub post_only: bool,
+    pub price_band: u16,
+    pub max_slot_price_move: u16,
+    pub max_mark_deviation: u16,
     pub timestamp: u64,
 }
 
 #[event]
-pub struct AskOrderAdded {
+pub struct OracleFallbackUsed {
     pub market: Pubkey,
-    pub order_id: u64,
-    pub client_id: u64,
-    pub user: Pubkey,
+    pub source: OracleSource,
     pub price: u64,
-    pub size: u64,
-    pub reduce_only: bool,
-    pub post_only: bool,
+    pub oracle_price_twap: u64,
     pub timestamp: u64,
 }
 
 #[event]
-pub struct OrderCancelled {
+pub struct OracleFallbackUpdated {
     pub market: Pubkey,
-    pub order_id: u64,
-    pub client_id: u64,
-    pub user: Pubkey,
-    pub side: Side,
-    pub price: u64,
+    pub secondary_oracle_feed_id: [u8; 32],
+    pub book_twap_fallback: bool,
+    pub max_oracle_deviation: u16,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct OracleParamsUpdated {
+    pub market: Pubkey,
+    pub max_oracle_age: u64,
+    pub max_oracle_confidence: u16,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct FundingParamsUpdated {
+    pub market: Pubkey,
+    pub funding_period: u64,
+    pub max_funding_rate: u16,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct TriggerOrderPlaced {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub order_id: u64,
+    pub kind: TriggerOrderKind,
+    pub side: Side,
+    pub trigger_price: u64,
+    pub limit_price: u64,
+    pub size: u64,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct TriggerOrderCancelled {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub order_id: u64,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct TriggerOrderExecuted {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub keeper: Pubkey,
+    pub order_id: u64,
+    pub kind: TriggerOrderKind,
+    pub side: Side,
+    pub trigger_price: u64,
+    pub oracle_price: u64,
+    pub filled_size: u64,
+    pub rested_size: u64,
+    pub keeper_fee: u64,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct TriggerOrderFeeUpdated {
+    pub market: Pubkey,
+    pub trigger_order_fee: u64,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct InsuranceParamsUpdated {
+    pub market: Pubkey,
+    pub insurance_fee_share: u16,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct InsuranceFundDeposited {
+    pub market: Pubkey,
+    pub depositor: Pubkey,
+    pub amount: u64,
+    pub balance: u64,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct InsuranceFundDrawn {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub bad_debt: u64,
+    pub amount: u64,
+    pub balance: u64,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct LossSocialized {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub side: Side,
+    pub amount: u64,
+    pub open_interest: u64,
+    pub socialized_loss_index: u64,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct LiquidationParamsUpdated {
+    pub market: Pubkey,
+    pub close_factor: u16,
+    pub max_slippage: u16,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct OrderMatched {
+    pub market: Pubkey,
+    pub order_id: u64,
+    pub maker_order_id: u64,
+    pub client_id: u64,
+    pub maker_client_id: u64,
+    pub user: Pubkey,
+    pub maker: Pubkey,
+    pub side: Side,
+    pub price: u64,
+    pub size: u64,
+    pub quote_amount: u64,
+    pub taker_fee: u64,
+    pub maker_rebate: u64,
+    pub remaining_size: u64,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct BidOrderAdded {
+    pub market: Pubkey,
+    pub order_id: u64,
+    pub client_id: u64,
+    pub user: Pubkey,
+    pub price: u64,
+    pub size: u64,
+    pub reduce_only: bool,
+    pub post_only: bool,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct AskOrderAdded {
+    pub market: Pubkey,
+    pub order_id: u64,
+    pub client_id: u64,
+    pub user: Pubkey,
+    pub price: u64,
+    pub size: u64,
+    pub reduce_only: bool,
+    pub post_only: bool,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct OrderExpired {
+    pub market: Pubkey,
+    pub order_id: u64,
+    pub client_id: u64,
+    pub user: Pubkey,
+    pub side: Side,
+    pub price: u64,
+    pub remaining_size: u64,
+    pub expiry: u64,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct OrderCancelled {
+    pub market: Pubkey,
+    pub order_id: u64,
+    pub client_id: u64,
+    pub user: Pubkey,
+    pub side: Side,
+    pub price: u64,
     pub remaining_size: u64,
     pub reduce_only: bool,
     pub timestamp: u64,
 }
 
+#[event]
+pub struct BalancesClaimed {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub base_amount: u64,
+    pub quote_amount: u64,
+    pub timestamp: u64,
+}
+
 #[event]
 pub struct FundingRateUpdated {
     pub market: Pubkey,
     pub oracle_price: u64,
     pub mark_price: u64,
+    pub oracle_price_twap: u64,
+    pub mark_price_twap: u64,
     pub premium_index: i64,
     pub funding_rate: i64,
     pub timestamp: u64,
@@ -442,31 +1141,87 @@ pub struct PositionLiquidated {
     pub market: Pubkey,
     pub user: Pubkey,
     pub liquidator: Pubkey,
+    pub mode: LiquidationMode,
     pub side: Side,
     pub size: u64,
+    pub remaining_size: u64,
     pub position_value: u64,
     pub maintenance_margin: u64,
     pub liquidation_fee: u64,
-    pub remaining: u64,
+    pub realized_pnl: i64,
+    pub remaining_collateral: u64,
     pub oracle_price: u64,
     pub timestamp: u64,
 }
 
 #[event]
-pub struct CollateralDeposited {
+pub struct PositionDeleveraged {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub bankrupt_user: Pubkey,
+    pub keeper: Pubkey,
+    pub side: Side,
+    pub size: u64,
+    pub remaining_size: u64,
+    pub bankruptcy_price: u64,
+    pub adl_score: u64,
+    pub realized_pnl: i64,
+    pub collateral: u64,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct AdlQueueUpdated {
     pub market: Pubkey,
+    pub longs: Vec<AdlQueueEntry>,
+    pub shorts: Vec<AdlQueueEntry>,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct CollateralDeposited {
+    pub margin_account: Pubkey,
     pub user: Pubkey,
     pub amount: u64,
-    pub total_margin: u64,
+    pub total_collateral: u64,
     pub timestamp: u64,
 }
 
 #[event]
 pub struct CollateralWithdrawn {
+    pub margin_account: Pubkey,
+    pub user: Pubkey,
+    pub amount: u64,
+    pub remaining_collateral: u64,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct PnlRealized {
     pub market: Pubkey,
     pub user: Pubkey,
+    pub price: u64,
+    pub pnl: i64,
+    pub collateral: u64,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct AssetParamsSynced {
+    pub market: Pubkey,
+    pub maintenance_margin_ratio: u16,
+    pub liquidation_fee: u16,
+    pub max_leverage: u16,
+    pub funding_rate_multiplier: u16,
+    pub active: bool,
+    pub timestamp: u64,
+}
+
+#[event]
+pub struct FeesCollected {
+    pub market: Pubkey,
+    pub destination: Pubkey,
     pub amount: u64,
-    pub remaining_margin: u64,
     pub timestamp: u64,
 }
 
@@ -476,7 +1231,7 @@ pub struct PositionUpdated {
     pub user: Pubkey,
     pub side: Side,
     pub size: u64,
-    pub margin: u64,
+    pub collateral: u64,
     pub entry_price: u64,
     pub leverage: u16,
     pub realized_pnl: i64,
@@ -564,6 +1319,69 @@ pub enum ErrorCode {
     
     #[msg("Market full - too many orders or positions")]
     MarketFull,
+    
+    #[msg("Missing or invalid token account")]
+    InvalidTokenAccount,
+    
+    #[msg("Not gov")]
+    NotGov,
+    
+    #[msg("Math overflow")]
+    MathOverflow,
+    
+    #[msg("Missing or invalid margin account")]
+    InvalidMarginAccount,
+    
+    #[msg("Too many open positions")]
+    TooManyPositions,
+    
+    #[msg("Position still has size or open orders")]
+    PositionNotEmpty,
+    
+    #[msg("No liquidity within the liquidation slippage limit")]
+    NoLiquidity,
+    
+    #[msg("Position can only be closed by liquidation or the insurance fund")]
+    DeleverageNotAllowed,
+    
+    #[msg("No profitable opposite positions to deleverage")]
+    NoDeleverageCandidates,
+    
+    #[msg("Oracle price is zero or negative")]
+    InvalidOraclePrice,
+    
+    #[msg("Oracle confidence interval too wide")]
+    OracleConfidenceTooWide,
+    
+    #[msg("Oracle price sources disagree")]
+    OracleDeviationTooLarge,
+    
+    #[msg("Fill price moved too far within one slot")]
+    PriceMoveTooLarge,
+    
+    #[msg("Order expiry must be in the future")]
+    InvalidExpiry,
+    
+    #[msg("Fill-or-kill order could not be filled in full")]
+    FillOrKillNotFilled,
+    
+    #[msg("Too many open trigger orders")]
+    TooManyTriggerOrders,
+    
+    #[msg("Trigger order not found")]
+    TriggerOrderNotFound,
+    
+    #[msg("Trigger price not reached")]
+    TriggerNotReached,
+    
+    #[msg("Bad debt could not be covered by the insurance fund or socialized")]
+    BadDebtNotCovered,
+    
+    #[msg("Every queued deleverage candidate must be passed")]
+    IncompleteDeleverageCandidates,
+    
+    #[msg("Claimable balances account not found")]
+    ClaimableBalancesNotFound,
 }
 
 #[derive(Accounts)]
@@ -575,12 +1393,9 @@ pub struct Initialize<'info> {
     )]
     pub market: Account<'info, Market>,
     
-    #[account(
-        init,
-        payer = authority,
-        space = 8 + Orderbook::SIZE
-    )]
-    pub orderbook: Account<'info, Orderbook>,
+    // Too large to create through CPI, so the client allocates it beforehand
+    #[account(zero)]
+    pub orderbook: AccountLoader<'info, Orderbook>,
     
     pub base_mint: Account<'info, Mint>,
     pub quote_mint: Account<'info, Mint>,
@@ -601,6 +1416,26 @@ pub struct Initialize<'info> {
     )]
     pub quote_vault: Account<'info, TokenAccount>,
     
+    #[account(
+        init,
+        payer = authority,
+        seeds = [b"fee_vault", market.key().as_ref()],
+        bump,
+        token::mint = quote_mint,
+        token::authority = vault_signer,
+    )]
+    pub fee_vault: Account<'info, TokenAccount>,
+    
+    #[account(
+        init,
+        payer = authority,
+        seeds = [b"insurance_fund", market.key().as_ref()],
+        bump,
+        token::mint = quote_mint,
+        token::authority = vault_signer,
+    )]
+    pub insurance_fund: Account<'info, TokenAccount>,
+    
     /// CHECK: The vault signer PDA
     #[account(
         seeds = [b"vault_signer", market.key().as_ref()],
@@ -622,14 +1457,14 @@ pub struct PlaceOrder<'info> {
     #[account(mut)]
     pub market: Account<'info, Market>,
     
-    #[account(mut, constraint = orderbook.market == market.key() @ ErrorCode::InvalidOrderbook)]
-    pub orderbook: Account<'info, Orderbook>,
+    #[account(mut, constraint = orderbook.load()?.market == market.key() @ ErrorCode::InvalidOrderbook)]
+    pub orderbook: AccountLoader<'info, Orderbook>,
     
     /// This account is optional for perpetual markets
-    #[account(mut)]
+    #[account(mut, constraint = user_base_account.mint == market.base_mint @ ErrorCode::InvalidTokenAccount)]
     pub user_base_account: Option<Account<'info, TokenAccount>>,
     
-    #[account(mut)]
+    #[account(mut, constraint = user_quote_account.mint == market.quote_mint @ ErrorCode::InvalidTokenAccount)]
     pub user_quote_account: Account<'info, TokenAccount>,
     
     #[account(mut, constraint = base_vault.key() == market.base_vault @ ErrorCode::InvalidVault)]
@@ -638,6 +1473,12 @@ pub struct PlaceOrder<'info> {
     #[account(mut, constraint = quote_vault.key() == market.quote_vault @ ErrorCode::InvalidVault)]
     pub quote_vault: Account<'info, TokenAccount>,
     
+    #[account(mut, constraint = fee_vault.key() == market.fee_vault @ ErrorCode::InvalidVault)]
+    pub fee_vault: Account<'info, TokenAccount>,
+    
+    #[account(mut, constraint = insurance_fund.key() == market.insurance_fund @ ErrorCode::InvalidVault)]
+    pub insurance_fund: Account<'info, TokenAccount>,
+    
     /// CHECK: The vault signer PDA
     #[account(
         seeds = [b"vault_signer", market.key().as_ref()],
@@ -652,9 +1493,48 @@ pub struct PlaceOrder<'info> {
     #[account(constraint = registry.key() == market.registry @ ErrorCode::InvalidRegistry)]
     pub registry: Account<'info, omniliquid_registry::Registry>,
     
-    /// Optional Pyth price feed for price validation
+    /// Primary Pyth price update: required for perpetual markets, even when
+    /// stale, and must be omitted for spot markets
+    #[account(constraint = pyth_price_feed.price_message.feed_id == market.oracle_feed_id @ ErrorCode::InvalidPriceFeed)]
     pub pyth_price_feed: Option<Account<'info, PriceUpdateV2>>,
     
+    /// Secondary feed used when the primary one fails; perpetual markets only
+    #[account(constraint = fallback_price_feed.price_message.feed_id == market.secondary_oracle_feed_id @ ErrorCode::InvalidPriceFeed)]
+    pub fallback_price_feed: Option<Account<'info, PriceUpdateV2>>,
+    
+    /// The user's position, required for perpetual markets
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), user.key().as_ref()],
+        bump = position.bump,
+    )]
+    pub position: Option<Account<'info, Position>>,
+    
+    /// The user's margin account, required for perpetual markets
+    #[account(
+        mut,
+        seeds = [b"margin_account", user.key().as_ref()],
+        bump = margin_account.bump,
+        constraint = margin_account.collateral_mint == market.quote_mint @ ErrorCode::InvalidMarginAccount,
+    )]
+    pub margin_account: Option<Account<'info, MarginAccount>>,
+    
+    #[account(mut, seeds = [b"collateral_vault", market.quote_mint.as_ref()], bump)]
+    pub collateral_vault: Option<Account<'info, TokenAccount>>,
+    
+    /// CHECK: The collateral vault signer PDA
+    #[account(seeds = [b"collateral_signer"], bump)]
+    pub collateral_signer: Option<AccountInfo<'info>>,
+    
+    /// The user's claimable balances, required for spot orders that rest so
+    /// their fills have somewhere to go. Only checked to exist; refunds due
+    /// to the user are credited through the remaining accounts like any maker's.
+    #[account(
+        seeds = [b"claimable_balances", market.key().as_ref(), user.key().as_ref()],
+        bump = claimable_balances.bump,
+    )]
+    pub claimable_balances: Option<Account<'info, ClaimableBalances>>,
+    
     pub token_program: Program<'info, Token>,
 }
 
@@ -663,19 +1543,19 @@ pub struct CancelOrder<'info> {
     #[account(mut)]
     pub market: Account<'info, Market>,
     
-    #[account(mut, constraint = orderbook.market == market.key() @ ErrorCode::InvalidOrderbook)]
-    pub orderbook: Account<'info, Orderbook>,
+    #[account(mut, constraint = orderbook.load()?.market == market.key() @ ErrorCode::InvalidOrderbook)]
+    pub orderbook: AccountLoader<'info, Orderbook>,
     
-    #[account(mut)]
+    #[account(mut, constraint = user_base_account.mint == market.base_mint @ ErrorCode::InvalidTokenAccount)]
     pub user_base_account: Option<Account<'info, TokenAccount>>,
     
-    #[account(mut)]
+    #[account(mut, constraint = user_quote_account.mint == market.quote_mint @ ErrorCode::InvalidTokenAccount)]
     pub user_quote_account: Option<Account<'info, TokenAccount>>,
     
-    #[account(mut)]
+    #[account(mut, constraint = base_vault.key() == market.base_vault @ ErrorCode::InvalidVault)]
     pub base_vault: Option<Account<'info, TokenAccount>>,
     
-    #[account(mut)]
+    #[account(mut, constraint = quote_vault.key() == market.quote_vault @ ErrorCode::InvalidVault)]
     pub quote_vault: Option<Account<'info, TokenAccount>>,
     
     /// CHECK: The vault signer PDA
@@ -692,26 +1572,122 @@ pub struct CancelOrder<'info> {
 }
 
 #[derive(Accounts)]
-pub struct ChangeMarketStatus<'info> {
-    #[account(mut, constraint = market.authority == authority.key() @ ErrorCode::InvalidAuthority)]
+pub struct InitClaimableBalances<'info> {
+    #[account(constraint = !market.is_perpetual @ ErrorCode::InvalidParameters)]
     pub market: Account<'info, Market>,
     
-    #[account(signer)]
-    pub authority: AccountInfo<'info>,
-}
-
-#[derive(Accounts)]
-pub struct UpdateFundingRate<'info> {
-    #[account(mut)]
-    pub market: Account<'info, Market>,
+    #[account(
+        init,
+        payer = user,
+        space = 8 + ClaimableBalances::SIZE,
+        seeds = [b"claimable_balances", market.key().as_ref(), user.key().as_ref()],
+        bump,
+    )]
+    pub claimable_balances: Account<'info, ClaimableBalances>,
+    
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimBalances<'info> {
+    pub market: Account<'info, Market>,
+    
+    #[account(
+        mut,
+        seeds = [b"claimable_balances", market.key().as_ref(), user.key().as_ref()],
+        bump = claimable_balances.bump,
+    )]
+    pub claimable_balances: Account<'info, ClaimableBalances>,
+    
+    #[account(mut, constraint = user_base_account.mint == market.base_mint @ ErrorCode::InvalidTokenAccount)]
+    pub user_base_account: Account<'info, TokenAccount>,
+    
+    #[account(mut, constraint = user_quote_account.mint == market.quote_mint @ ErrorCode::InvalidTokenAccount)]
+    pub user_quote_account: Account<'info, TokenAccount>,
+    
+    #[account(mut, constraint = base_vault.key() == market.base_vault @ ErrorCode::InvalidVault)]
+    pub base_vault: Account<'info, TokenAccount>,
+    
+    #[account(mut, constraint = quote_vault.key() == market.quote_vault @ ErrorCode::InvalidVault)]
+    pub quote_vault: Account<'info, TokenAccount>,
+    
+    /// CHECK: The vault signer PDA
+    #[account(
+        seeds = [b"vault_signer", market.key().as_ref()],
+        bump = market.vault_signer_bump,
+    )]
+    pub vault_signer: AccountInfo<'info>,
+    
+    #[account(signer)]
+    pub user: AccountInfo<'info>,
+    
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct ChangeMarketStatus<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
     
     #[account(constraint = registry.key() == market.registry @ ErrorCode::InvalidRegistry)]
     pub registry: Account<'info, omniliquid_registry::Registry>,
     
-    pub pyth_price_feed: Account<'info, PriceUpdateV2>,
+    /// The market authority, or the registry's gov, which alone can reactivate a paused or closed market
+    #[account(
+        signer,
+        constraint = authority.key() == market.authority || authority.key() == registry.gov @ ErrorCode::InvalidAuthority,
+    )]
+    pub authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateMarketParams<'info> {
+    #[account(mut, constraint = market.authority == authority.key() @ ErrorCode::InvalidAuthority)]
+    pub market: Account<'info, Market>,
+    
+    #[account(signer)]
+    pub authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DepositInsuranceFund<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    
+    #[account(mut, constraint = insurance_fund.key() == market.insurance_fund @ ErrorCode::InvalidVault)]
+    pub insurance_fund: Account<'info, TokenAccount>,
     
+    #[account(mut, constraint = depositor_quote_account.mint == market.quote_mint @ ErrorCode::InvalidTokenAccount)]
+    pub depositor_quote_account: Account<'info, TokenAccount>,
+    
+    #[account(signer)]
+    pub depositor: AccountInfo<'info>,
+    
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateFundingRate<'info> {
     #[account(mut)]
-    pub orderbook: Account<'info, Orderbook>,
+    pub market: Account<'info, Market>,
+    
+    #[account(constraint = registry.key() == market.registry @ ErrorCode::InvalidRegistry)]
+    pub registry: Account<'info, omniliquid_registry::Registry>,
+    
+    /// Primary Pyth price update, always required; a stale or invalid one
+    /// falls back to `fallback_price_feed`
+    #[account(constraint = pyth_price_feed.price_message.feed_id == market.oracle_feed_id @ ErrorCode::InvalidPriceFeed)]
+    pub pyth_price_feed: Account<'info, PriceUpdateV2>,
+    
+    /// Secondary feed used when the primary one fails
+    #[account(constraint = fallback_price_feed.price_message.feed_id == market.secondary_oracle_feed_id @ ErrorCode::InvalidPriceFeed)]
+    pub fallback_price_feed: Option<Account<'info, PriceUpdateV2>>,
+    
+    #[account(constraint = orderbook.load()?.market == market.key() @ ErrorCode::InvalidOrderbook)]
+    pub orderbook: AccountLoader<'info, Orderbook>,
     
     #[account(mut, signer)]
     pub authority: AccountInfo<'info>,
@@ -726,16 +1702,27 @@ pub struct LiquidatePosition<'info> {
     #[account(constraint = registry.key() == market.registry @ ErrorCode::InvalidRegistry)]
     pub registry: Account<'info, omniliquid_registry::Registry>,
     
+    /// Primary Pyth price update, always required; a stale or invalid one
+    /// falls back to `fallback_price_feed`
+    #[account(constraint = pyth_price_feed.price_message.feed_id == market.oracle_feed_id @ ErrorCode::InvalidPriceFeed)]
     pub pyth_price_feed: Account<'info, PriceUpdateV2>,
     
-    #[account(mut, constraint = quote_vault.key() == market.quote_vault @ ErrorCode::InvalidVault)]
-    pub quote_vault: Account<'info, TokenAccount>,
+    /// Secondary feed used when the primary one fails
+    #[account(constraint = fallback_price_feed.price_message.feed_id == market.secondary_oracle_feed_id @ ErrorCode::InvalidPriceFeed)]
+    pub fallback_price_feed: Option<Account<'info, PriceUpdateV2>>,
+    
+    #[account(mut, seeds = [b"collateral_vault", market.quote_mint.as_ref()], bump)]
+    pub collateral_vault: Account<'info, TokenAccount>,
     
     #[account(mut)]
     pub liquidator_quote_account: Account<'info, TokenAccount>,
     
-    #[account(mut)]
-    pub user_quote_account: Account<'info, TokenAccount>,
+    /// CHECK: The collateral vault signer PDA
+    #[account(seeds = [b"collateral_signer"], bump)]
+    pub collateral_signer: AccountInfo<'info>,
+    
+    #[account(mut, constraint = insurance_fund.key() == market.insurance_fund @ ErrorCode::InvalidVault)]
+    pub insurance_fund: Account<'info, TokenAccount>,
     
     /// CHECK: The vault signer PDA
     #[account(
@@ -748,27 +1735,97 @@ pub struct LiquidatePosition<'info> {
     /// CHECK: Not a signer, verified in the program
     pub user: AccountInfo<'info>,
     
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), user.key().as_ref()],
+        bump = position.bump,
+    )]
+    pub position: Account<'info, Position>,
+    
+    #[account(
+        mut,
+        seeds = [b"margin_account", user.key().as_ref()],
+        bump = margin_account.bump,
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    
+    #[account(mut, constraint = orderbook.load()?.market == market.key() @ ErrorCode::InvalidOrderbook)]
+    pub orderbook: AccountLoader<'info, Orderbook>,
+    
+    /// The liquidator's position, required to take over positions by transfer
+    /// and must be omitted for orderbook liquidations
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), liquidator.key().as_ref()],
+        bump = liquidator_position.bump,
+    )]
+    pub liquidator_position: Option<Account<'info, Position>>,
+    
+    /// The liquidator's margin account, passed exactly when its position is
+    #[account(
+        mut,
+        seeds = [b"margin_account", liquidator.key().as_ref()],
+        bump = liquidator_margin_account.bump,
+        constraint = liquidator_margin_account.collateral_mint == market.quote_mint @ ErrorCode::InvalidMarginAccount,
+    )]
+    pub liquidator_margin_account: Option<Account<'info, MarginAccount>>,
+    
     #[account(mut, signer)]
     pub liquidator: AccountInfo<'info>,
     
     pub token_program: Program<'info, Token>,
 }
-// For ManageCollateral
 #[derive(Accounts)]
-pub struct ManageCollateral<'info> {
+pub struct AutoDeleverage<'info> {
     #[account(mut)]
     pub market: Account<'info, Market>,
     
     #[account(constraint = registry.key() == market.registry @ ErrorCode::InvalidRegistry)]
     pub registry: Account<'info, omniliquid_registry::Registry>,
     
-    pub pyth_price_feed: Option<Account<'info, PriceUpdateV2>>,
+    /// Primary Pyth price update, always required; a stale or invalid one
+    /// falls back to `fallback_price_feed`
+    #[account(constraint = pyth_price_feed.price_message.feed_id == market.oracle_feed_id @ ErrorCode::InvalidPriceFeed)]
+    pub pyth_price_feed: Account<'info, PriceUpdateV2>,
     
-    #[account(mut, constraint = quote_vault.key() == market.quote_vault @ ErrorCode::InvalidVault)]
-    pub quote_vault: Account<'info, TokenAccount>,
+    /// Secondary feed used when the primary one fails
+    #[account(constraint = fallback_price_feed.price_message.feed_id == market.secondary_oracle_feed_id @ ErrorCode::InvalidPriceFeed)]
+    pub fallback_price_feed: Option<Account<'info, PriceUpdateV2>>,
     
-    #[account(mut)]
-    pub user_quote_account: Account<'info, TokenAccount>,
+    /// The bankrupt account being deleveraged
+    /// CHECK: Not a signer, verified in the program
+    pub user: AccountInfo<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), user.key().as_ref()],
+        bump = position.bump,
+    )]
+    pub position: Account<'info, Position>,
+    
+    #[account(
+        mut,
+        seeds = [b"margin_account", user.key().as_ref()],
+        bump = margin_account.bump,
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    
+    #[account(constraint = orderbook.load()?.market == market.key() @ ErrorCode::InvalidOrderbook)]
+    pub orderbook: AccountLoader<'info, Orderbook>,
+    
+    /// Ranked opposite positions the bankrupt position is closed against
+    #[account(mut, seeds = [b"adl_queue", market.key().as_ref()], bump = adl_queue.bump)]
+    pub adl_queue: Account<'info, AdlQueue>,
+    
+    #[account(mut, seeds = [b"collateral_vault", market.quote_mint.as_ref()], bump)]
+    pub collateral_vault: Account<'info, TokenAccount>,
+    
+    /// CHECK: The collateral vault signer PDA
+    #[account(seeds = [b"collateral_signer"], bump)]
+    pub collateral_signer: AccountInfo<'info>,
+    
+    #[account(mut, constraint = insurance_fund.key() == market.insurance_fund @ ErrorCode::InvalidVault)]
+    pub insurance_fund: Account<'info, TokenAccount>,
     
     /// CHECK: The vault signer PDA
     #[account(
@@ -777,156 +1834,1109 @@ pub struct ManageCollateral<'info> {
     )]
     pub vault_signer: AccountInfo<'info>,
     
-    #[account(mut, signer)]
-    pub user: AccountInfo<'info>,
+    #[account(signer)]
+    pub keeper: AccountInfo<'info>,
     
     pub token_program: Program<'info, Token>,
 }
 
-// Helper function to convert hex string to feed ID
-pub fn get_feed_id_from_hex(hex_string: &str) -> Result<[u8; 32]> {
-    let mut feed_id = [0u8; 32];
-    let hex_string = hex_string.strip_prefix("0x").unwrap_or(hex_string);
+#[derive(Accounts)]
+pub struct InitAdlQueue<'info> {
+    #[account(constraint = market.is_perpetual @ ErrorCode::NotPerpetualMarket)]
+    pub market: Account<'info, Market>,
     
-    require!(hex_string.len() == 64, ErrorCode::InvalidParameters);
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + AdlQueue::SIZE,
+        seeds = [b"adl_queue", market.key().as_ref()],
+        bump,
+    )]
+    pub adl_queue: Account<'info, AdlQueue>,
     
-    for i in 0..32 {
-        let byte_str = &hex_string[i * 2..i * 2 + 2];
-        feed_id[i] = u8::from_str_radix(byte_str, 16).map_err(|_| ErrorCode::InvalidParameters)?;
-    }
+    #[account(mut)]
+    pub payer: Signer<'info>,
     
-    Ok(feed_id)
+    pub system_program: Program<'info, System>,
 }
 
-// Helper functions for Pyth price feed
-fn get_pyth_price(price_update: &Account<PriceUpdateV2>, market: &Account<Market>) -> Result<u64> {
-    // Maximum age check is now handled by get_price_no_older_than
-    let price = price_update.get_price_no_older_than(
-        &Clock::get()?,
-        market.max_oracle_age,
-        &market.oracle_feed_id
-    ).map_err(|_| ErrorCode::InvalidPriceFeed)?;
+#[derive(Accounts)]
+pub struct UpdateAdlQueue<'info> {
+    pub market: Account<'info, Market>,
     
-    // Convert to 6 decimals (standard for our pricing)
-    let exponent = price.exponent;
-    let mantissa = price.price;
-    
-    // Calculate the scaled price with 6 decimal places
-    let scaled_price = if exponent < -6 {
-        // If price has more precision than 6 decimals, truncate
-        let factor = 10i64.pow((-6 - exponent) as u32);
-        mantissa.abs() as u64 / factor as u64
-    } else {
-        // If price has less precision, multiply
-        let factor = 10i64.pow((exponent + 6) as u32);
-        mantissa.abs() as u64 * factor as u64
-    };
+    /// Primary Pyth price update, always required; a stale or invalid one
+    /// falls back to `fallback_price_feed`
+    #[account(constraint = pyth_price_feed.price_message.feed_id == market.oracle_feed_id @ ErrorCode::InvalidPriceFeed)]
+    pub pyth_price_feed: Account<'info, PriceUpdateV2>,
     
-    Ok(scaled_price)
+    /// Secondary feed used when the primary one fails
+    #[account(constraint = fallback_price_feed.price_message.feed_id == market.secondary_oracle_feed_id @ ErrorCode::InvalidPriceFeed)]
+    pub fallback_price_feed: Option<Account<'info, PriceUpdateV2>>,
+    
+    #[account(mut, seeds = [b"adl_queue", market.key().as_ref()], bump = adl_queue.bump)]
+    pub adl_queue: Account<'info, AdlQueue>,
 }
-// Program implementation start
-#[program]
-pub mod omniliquid_clob {
-    use super::*;
 
+#[derive(Accounts)]
+pub struct InitTriggerOrders<'info> {
+    pub market: Account<'info, Market>,
+    
+    #[account(
+        init,
+        payer = user,
+        space = 8 + TriggerOrders::SIZE,
+        seeds = [b"trigger_orders", market.key().as_ref(), user.key().as_ref()],
+        bump,
+    )]
+    pub trigger_orders: Account<'info, TriggerOrders>,
+    
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
 
-    pub fn initialize(
-        ctx: Context<Initialize>,
-        market_name: String,
-        market_symbol: String,
-        asset_id: String,
-        is_perpetual: bool,
-        settle_with_usdc: bool,
-        min_base_order_size: u64,
-        tick_size: u64,
-        taker_fee_bps: u16,
-        maker_rebate_bps: u16,
-        max_leverage: u16,
-        funding_interval: u64,
-        vault_signer_bump: u8,
-        registry: Pubkey,
-        oracle_feed_id_hex: String,
-        max_oracle_age: u64,
-    ) -> Result<()> {
-        // Validate parameters
-        require!(
-            taker_fee_bps <= 500 && maker_rebate_bps <= taker_fee_bps,
-            ErrorCode::InvalidParameters
-        );
-        
-        require!(
-            min_base_order_size > 0 && tick_size > 0,
-            ErrorCode::InvalidParameters
-        );
-        
-        require!(
-            market_name.len() <= 32 && market_symbol.len() <= 16 && asset_id.len() <= 16,
-            ErrorCode::InvalidParameters
-        );
-        
-        require!(max_leverage > 0 && max_leverage <= 10000, ErrorCode::InvalidParameters);
-        
-        // Parse oracle feed ID
-        let oracle_feed_id = get_feed_id_from_hex(&oracle_feed_id_hex)?;
-        
-        let market = &mut ctx.accounts.market;
-        let orderbook = &mut ctx.accounts.orderbook;
-        
-        // Initialize market
-        market.authority = ctx.accounts.authority.key();
-        market.base_mint = ctx.accounts.base_mint.key();
-        market.quote_mint = ctx.accounts.quote_mint.key();
-        market.base_vault = ctx.accounts.base_vault.key();
-        market.quote_vault = ctx.accounts.quote_vault.key();
-        market.vault_signer_bump = vault_signer_bump;
-        market.registry = registry;
-        
-        // Set basic parameters
-        market.min_base_order_size = min_base_order_size;
-        market.tick_size = tick_size;
-        market.taker_fee_bps = taker_fee_bps;
-        market.maker_rebate_bps = maker_rebate_bps;
-        market.max_leverage = max_leverage;
-        
-        // Initialize order tracking
-        market.next_order_id = 1;
-        market.next_client_id = 1;
-        market.status = MarketStatus::Active;
-        
-        // Set market metadata
-        market.market_name = market_name.clone();
-        market.market_symbol = market_symbol.clone();
-        market.asset_id = asset_id.clone();
-        market.is_perpetual = is_perpetual;
-        market.settle_with_usdc = settle_with_usdc;
-        
-        // Initialize perpetual market data
-        let current_timestamp = Clock::get()?.unix_timestamp as u64;
-        market.last_funding_timestamp = current_timestamp;
-        market.last_oracle_price = 0;
-        market.oracle_price_offset = 0;
-        market.mark_price_twap = 0;
-        market.open_interest_long = 0;
-        market.open_interest_short = 0;
-        market.cumulative_funding_long = 0;
-        market.cumulative_funding_short = 0;
-        market.funding_interval = funding_interval;
-        
-        // Set oracle parameters
-        market.oracle_feed_id = oracle_feed_id;
-        market.max_oracle_age = max_oracle_age;
-        
-        // Initialize positions and orderbook
-        market.user_positions = Vec::new();
-        orderbook.market = market.key();
-        orderbook.bids = Vec::new();
-        orderbook.asks = Vec::new();
-        
-        emit!(MarketCreated {
-            market: market.key(),
-            base_mint: market.base_mint,
-            quote_mint: market.quote_mint,
-            market_name,
+#[derive(Accounts)]
+pub struct PlaceTriggerOrder<'info> {
+    pub market: Account<'info, Market>,
+    
+    /// Primary Pyth price update, always required; a stale or invalid one
+    /// falls back to `fallback_price_feed`
+    #[account(constraint = pyth_price_feed.price_message.feed_id == market.oracle_feed_id @ ErrorCode::InvalidPriceFeed)]
+    pub pyth_price_feed: Account<'info, PriceUpdateV2>,
+    
+    /// Secondary feed used when the primary one fails
+    #[account(constraint = fallback_price_feed.price_message.feed_id == market.secondary_oracle_feed_id @ ErrorCode::InvalidPriceFeed)]
+    pub fallback_price_feed: Option<Account<'info, PriceUpdateV2>>,
+    
+    #[account(
+        mut,
+        seeds = [b"trigger_orders", market.key().as_ref(), user.key().as_ref()],
+        bump = trigger_orders.bump,
+    )]
+    pub trigger_orders: Account<'info, TriggerOrders>,
+    
+    #[account(signer)]
+    pub user: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ManageTriggerOrders<'info> {
+    pub market: Account<'info, Market>,
+    
+    #[account(
+        mut,
+        seeds = [b"trigger_orders", market.key().as_ref(), user.key().as_ref()],
+        bump = trigger_orders.bump,
+    )]
+    pub trigger_orders: Account<'info, TriggerOrders>,
+    
+    #[account(signer)]
+    pub user: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteTriggerOrder<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    
+    #[account(constraint = registry.key() == market.registry @ ErrorCode::InvalidRegistry)]
+    pub registry: Account<'info, omniliquid_registry::Registry>,
+    
+    /// Primary Pyth price update, always required; a stale or invalid one
+    /// falls back to `fallback_price_feed`
+    #[account(constraint = pyth_price_feed.price_message.feed_id == market.oracle_feed_id @ ErrorCode::InvalidPriceFeed)]
+    pub pyth_price_feed: Account<'info, PriceUpdateV2>,
+    
+    /// Secondary feed used when the primary one fails
+    #[account(constraint = fallback_price_feed.price_message.feed_id == market.secondary_oracle_feed_id @ ErrorCode::InvalidPriceFeed)]
+    pub fallback_price_feed: Option<Account<'info, PriceUpdateV2>>,
+    
+    #[account(mut, constraint = orderbook.load()?.market == market.key() @ ErrorCode::InvalidOrderbook)]
+    pub orderbook: AccountLoader<'info, Orderbook>,
+    
+    /// The owner of the trigger order
+    /// CHECK: Not a signer, verified in the program
+    pub user: AccountInfo<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"trigger_orders", market.key().as_ref(), user.key().as_ref()],
+        bump = trigger_orders.bump,
+    )]
+    pub trigger_orders: Account<'info, TriggerOrders>,
+    
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), user.key().as_ref()],
+        bump = position.bump,
+    )]
+    pub position: Account<'info, Position>,
+    
+    #[account(
+        mut,
+        seeds = [b"margin_account", user.key().as_ref()],
+        bump = margin_account.bump,
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    
+    #[account(mut, seeds = [b"collateral_vault", market.quote_mint.as_ref()], bump)]
+    pub collateral_vault: Account<'info, TokenAccount>,
+    
+    /// CHECK: The collateral vault signer PDA
+    #[account(seeds = [b"collateral_signer"], bump)]
+    pub collateral_signer: AccountInfo<'info>,
+    
+    #[account(mut, constraint = fee_vault.key() == market.fee_vault @ ErrorCode::InvalidVault)]
+    pub fee_vault: Account<'info, TokenAccount>,
+    
+    #[account(mut, constraint = insurance_fund.key() == market.insurance_fund @ ErrorCode::InvalidVault)]
+    pub insurance_fund: Account<'info, TokenAccount>,
+    
+    /// CHECK: The vault signer PDA
+    #[account(
+        seeds = [b"vault_signer", market.key().as_ref()],
+        bump = market.vault_signer_bump,
+    )]
+    pub vault_signer: AccountInfo<'info>,
+    
+    #[account(mut, constraint = keeper_quote_account.mint == market.quote_mint @ ErrorCode::InvalidTokenAccount)]
+    pub keeper_quote_account: Account<'info, TokenAccount>,
+    
+    #[account(signer)]
+    pub keeper: AccountInfo<'info>,
+    
+    pub token_program: Program<'info, Token>,
+}
+
+// For ManageCollateral
+#[derive(Accounts)]
+pub struct ManageCollateral<'info> {
+    #[account(
+        mut,
+        seeds = [b"margin_account", user.key().as_ref()],
+        bump = margin_account.bump,
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    
+    #[account(mut, seeds = [b"collateral_vault", margin_account.collateral_mint.as_ref()], bump)]
+    pub collateral_vault: Account<'info, TokenAccount>,
+    
+    #[account(mut, constraint = user_quote_account.mint == margin_account.collateral_mint @ ErrorCode::InvalidTokenAccount)]
+    pub user_quote_account: Account<'info, TokenAccount>,
+    
+    /// CHECK: The collateral vault signer PDA
+    #[account(seeds = [b"collateral_signer"], bump)]
+    pub collateral_signer: AccountInfo<'info>,
+    
+    #[account(mut, signer)]
+    pub user: AccountInfo<'info>,
+    
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct InitCollateralVault<'info> {
+    pub collateral_mint: Account<'info, Mint>,
+    
+    #[account(
+        init,
+        payer = payer,
+        seeds = [b"collateral_vault", collateral_mint.key().as_ref()],
+        bump,
+        token::mint = collateral_mint,
+        token::authority = collateral_signer,
+    )]
+    pub collateral_vault: Account<'info, TokenAccount>,
+    
+    /// CHECK: The collateral vault signer PDA
+    #[account(seeds = [b"collateral_signer"], bump)]
+    pub collateral_signer: AccountInfo<'info>,
+    
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct InitMarginAccount<'info> {
+    pub collateral_mint: Account<'info, Mint>,
+    
+    #[account(
+        init,
+        payer = user,
+        space = 8 + MarginAccount::SIZE,
+        seeds = [b"margin_account", user.key().as_ref()],
+        bump,
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitPosition<'info> {
+    pub market: Account<'info, Market>,
+    
+    #[account(
+        init,
+        payer = user,
+        space = 8 + Position::SIZE,
+        seeds = [b"position", market.key().as_ref(), user.key().as_ref()],
+        bump,
+    )]
+    pub position: Account<'info, Position>,
+    
+    #[account(
+        mut,
+        seeds = [b"margin_account", user.key().as_ref()],
+        bump = margin_account.bump,
+        constraint = margin_account.collateral_mint == market.quote_mint @ ErrorCode::InvalidMarginAccount,
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePosition<'info> {
+    pub market: Account<'info, Market>,
+    
+    #[account(constraint = orderbook.load()?.market == market.key() @ ErrorCode::InvalidOrderbook)]
+    pub orderbook: AccountLoader<'info, Orderbook>,
+    
+    #[account(
+        mut,
+        close = user,
+        seeds = [b"position", market.key().as_ref(), user.key().as_ref()],
+        bump = position.bump,
+    )]
+    pub position: Account<'info, Position>,
+    
+    #[account(
+        mut,
+        seeds = [b"margin_account", user.key().as_ref()],
+        bump = margin_account.bump,
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    
+    #[account(mut)]
+    pub user: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SyncAssetParams<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    
+    #[account(constraint = registry.key() == market.registry @ ErrorCode::InvalidRegistry)]
+    pub registry: Account<'info, omniliquid_registry::Registry>,
+}
+
+#[derive(Accounts)]
+pub struct CollectFees<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    
+    #[account(constraint = registry.key() == market.registry @ ErrorCode::InvalidRegistry)]
+    pub registry: Account<'info, omniliquid_registry::Registry>,
+    
+    #[account(mut, constraint = fee_vault.key() == market.fee_vault @ ErrorCode::InvalidVault)]
+    pub fee_vault: Account<'info, TokenAccount>,
+    
+    /// Treasury or OLP vault token account receiving the fees
+    #[account(mut, constraint = destination.mint == market.quote_mint @ ErrorCode::InvalidTokenAccount)]
+    pub destination: Account<'info, TokenAccount>,
+    
+    /// CHECK: The vault signer PDA
+    #[account(
+        seeds = [b"vault_signer", market.key().as_ref()],
+        bump = market.vault_signer_bump,
+    )]
+    pub vault_signer: AccountInfo<'info>,
+    
+    #[account(signer, constraint = gov.key() == registry.gov @ ErrorCode::NotGov)]
+    pub gov: AccountInfo<'info>,
+    
+    pub token_program: Program<'info, Token>,
+}
+
+// Helper function to convert hex string to feed ID
+pub fn get_feed_id_from_hex(hex_string: &str) -> Result<[u8; 32]> {
+    let mut feed_id = [0u8; 32];
+    let hex_string = hex_string.strip_prefix("0x").unwrap_or(hex_string);
+    
+    require!(hex_string.len() == 64, ErrorCode::InvalidParameters);
+    
+    for i in 0..32 {
+        let byte_str = &hex_string[i * 2..i * 2 + 2];
+        feed_id[i] = u8::from_str_radix(byte_str, 16).map_err(|_| ErrorCode::InvalidParameters)?;
+    }
+    
+    Ok(feed_id)
+}
+
+// Load a user's position for the given market from the remaining accounts
+fn load_user_position<'info>(
+    accounts: &'info [AccountInfo<'info>],
+    market: &Pubkey,
+    owner: &Pubkey,
+) -> Result<Account<'info, Position>> {
+    for account_info in accounts {
+        if let Ok(position) = Account::<Position>::try_from(account_info) {
+            if position.market == *market && position.owner == *owner {
+                return Ok(position);
+            }
+        }
+    }
+    
+    Err(ErrorCode::PositionNotFound.into())
+}
+
+// Credit spot tokens to a user's claimable balances in `market`, loaded from
+// the remaining accounts and written back straight away so the same user can
+// be credited again later in the instruction
+fn credit_claimable<'info>(
+    accounts: &'info [AccountInfo<'info>],
+    market: &Pubkey,
+    owner: &Pubkey,
+    base_amount: u64,
+    quote_amount: u64,
+) -> Result<()> {
+    if base_amount == 0 && quote_amount == 0 {
+        return Ok(());
+    }
+    
+    for account_info in accounts {
+        if let Ok(mut claimable_balances) = Account::<ClaimableBalances>::try_from(account_info) {
+            if claimable_balances.market == *market && claimable_balances.owner == *owner {
+                claimable_balances.credit(base_amount, quote_amount)?;
+                return claimable_balances.exit(&crate::ID);
+            }
+        }
+    }
+    
+    Err(ErrorCode::ClaimableBalancesNotFound.into())
+}
+
+// Load a user's margin account from the remaining accounts
+fn load_margin_account<'info>(
+    accounts: &'info [AccountInfo<'info>],
+    owner: &Pubkey,
+) -> Result<Account<'info, MarginAccount>> {
+    for account_info in accounts {
+        if let Ok(margin_account) = Account::<MarginAccount>::try_from(account_info) {
+            if margin_account.owner == *owner {
+                return Ok(margin_account);
+            }
+        }
+    }
+    
+    Err(ErrorCode::InvalidMarginAccount.into())
+}
+
+// Token accounts perp settlement moves collateral, fees and bad-debt cover through
+pub struct PerpVaults<'info> {
+    pub token_program: AccountInfo<'info>,
+    pub collateral_vault: AccountInfo<'info>,
+    pub collateral_signer: AccountInfo<'info>,
+    pub collateral_signer_bump: u8,
+    pub insurance_fund: AccountInfo<'info>,
+    pub vault_signer: AccountInfo<'info>,
+}
+
+// Cover a loss a user's collateral could not absorb: draw on the insurance
+// fund first, then spread the rest over the open interest on the other side,
+// which took the matching gains
+fn cover_bad_debt<'info>(
+    market: &mut Account<'info, Market>,
+    vaults: &PerpVaults<'info>,
+    user: Pubkey,
+    bankrupt_side: Side,
+    bad_debt: u64,
+    timestamp: u64,
+) -> Result<()> {
+    if bad_debt == 0 {
+        return Ok(());
+    }
+    
+    let market_key = market.key();
+    let covered = std::cmp::min(bad_debt, market.insurance_fund_balance);
+    
+    if covered > 0 {
+        let seeds = &[
+            b"vault_signer".as_ref(),
+            market_key.as_ref(),
+            &[market.vault_signer_bump],
+        ];
+        let signer = &[&seeds[..]];
+        
+        token::transfer(
+            CpiContext::new_with_signer(
+                vaults.token_program.clone(),
+                Transfer {
+                    from: vaults.insurance_fund.clone(),
+                    to: vaults.collateral_vault.clone(),
+                    authority: vaults.vault_signer.clone(),
+                },
+                signer,
+            ),
+            covered,
+        )?;
+        
+        market.insurance_fund_balance = market.insurance_fund_balance
+            .checked_sub(covered)
+            .ok_or(ErrorCode::MathOverflow)?;
+        
+        emit!(InsuranceFundDrawn {
+            market: market_key,
+            user,
+            bad_debt,
+            amount: covered,
+            balance: market.insurance_fund_balance,
+            timestamp,
+        });
+    }
+    
+    // Open interest is balanced, so the other side is only empty once every
+    // position has closed and there is nobody left to charge
+    let shortfall = bad_debt - covered;
+    if shortfall > 0 {
+        require!(market.socialize_loss(bankrupt_side, shortfall)? > 0, ErrorCode::BadDebtNotCovered);
+        
+        let counter_side = match bankrupt_side {
+            Side::Bid => Side::Ask,
+            Side::Ask => Side::Bid,
+        };
+        
+        emit!(LossSocialized {
+            market: market_key,
+            user,
+            side: counter_side,
+            amount: shortfall,
+            open_interest: match counter_side {
+                Side::Bid => market.open_interest_long,
+                Side::Ask => market.open_interest_short,
+            },
+            socialized_loss_index: market.socialized_loss_index(counter_side),
+            timestamp,
+        });
+    }
+    
+    Ok(())
+}
+
+// The taker side of a perp trade against the book
+pub struct PerpTrade {
+    pub user: Pubkey,
+    pub side: Side,
+    pub order_id: u64,
+    pub client_id: u64,
+    pub size: u64,
+    // Liquidations close without trading fees
+    pub pays_fees: bool,
+    pub timestamp: u64,
+}
+
+// Totals from settling a perp taker's fills
+#[derive(Default)]
+pub struct PerpSettlement {
+    pub filled_size: u64,
+    pub realized_pnl: i64,
+    // Loss the taker's collateral could not cover, left for the caller to
+    // reject or cover
+    pub bad_debt: u64,
+    pub taker_fee: u64,
+    // Taker fees less maker rebates, still held in the collateral vault
+    pub protocol_fee: u64,
+}
+
+// Apply a taker's fills to its position and to each maker's, whose position
+// and margin account are passed in the remaining accounts. Makers' PnL and
+// rebates are settled into collateral, with any bad debt they leave covered.
+// The taker's PnL is settled once over all fills and its fee charged.
+fn settle_perp_fills<'info>(
+    market: &mut Account<'info, Market>,
+    vaults: &PerpVaults<'info>,
+    accounts: &'info [AccountInfo<'info>],
+    trade: &PerpTrade,
+    position: &mut Position,
+    margin_account: &mut MarginAccount,
+    fills: &[Fill],
+) -> Result<PerpSettlement> {
+    let market_key = market.key();
+    let timestamp = trade.timestamp;
+    let maker_side = match trade.side {
+        Side::Bid => Side::Ask,
+        Side::Ask => Side::Bid,
+    };
+    let mut settlement = PerpSettlement::default();
+    
+    for fill in fills.iter() {
+        settlement.filled_size += fill.size;
+        
+        let quote_amount = math::notional(fill.size, fill.price)?;
+        let (taker_fee, maker_rebate) = if trade.pays_fees {
+            (
+                math::bps_of(quote_amount, market.taker_fee_bps)?,
+                math::bps_of(quote_amount, market.maker_rebate_bps)?,
+            )
+        } else {
+            (0, 0)
+        };
+        settlement.taker_fee = settlement.taker_fee.checked_add(taker_fee).ok_or(ErrorCode::MathOverflow)?;
+        settlement.protocol_fee = settlement.protocol_fee
+            .checked_add(taker_fee - maker_rebate)
+            .ok_or(ErrorCode::MathOverflow)?;
+        
+        let realized_pnl = market.apply_fill(position, trade.side, fill.price, fill.size, timestamp)?;
+        settlement.realized_pnl = settlement.realized_pnl.checked_add(realized_pnl).ok_or(ErrorCode::MathOverflow)?;
+        
+        // A resting maker can't be rejected without blocking the book, so its
+        // bad debt is covered
+        let mut maker_position = load_user_position(accounts, &market_key, &fill.maker)?;
+        let mut maker_margin_account = load_margin_account(accounts, &fill.maker)?;
+        let maker_position_side = maker_position.side;
+        let maker_pnl = market.apply_fill(&mut maker_position, maker_side, fill.price, fill.size, timestamp)?;
+        let maker_bad_debt = maker_margin_account.settle_pnl(&mut maker_position)?;
+        cover_bad_debt(market, vaults, fill.maker, maker_position_side, maker_bad_debt, timestamp)?;
+        maker_margin_account.collateral = maker_margin_account.collateral
+            .checked_add(maker_rebate)
+            .ok_or(ErrorCode::MathOverflow)?;
+        
+        emit!(OrderMatched {
+            market: market_key,
+            order_id: trade.order_id,
+            maker_order_id: fill.maker_order_id,
+            client_id: trade.client_id,
+            maker_client_id: fill.maker_client_id,
+            user: trade.user,
+            maker: fill.maker,
+            side: trade.side,
+            price: fill.price,
+            size: fill.size,
+            quote_amount,
+            taker_fee,
+            maker_rebate,
+            remaining_size: trade.size - settlement.filled_size,
+            timestamp,
+        });
+        
+        if maker_pnl != 0 {
+            emit!(PnlRealized {
+                market: market_key,
+                user: fill.maker,
+                price: fill.price,
+                pnl: maker_pnl,
+                collateral: maker_margin_account.collateral,
+                timestamp,
+            });
+        }
+        
+        emit!(PositionUpdated {
+            market: market_key,
+            user: fill.maker,
+            side: maker_position.side,
+            size: maker_position.size,
+            collateral: maker_margin_account.collateral,
+            entry_price: maker_position.entry_price,
+            leverage: maker_position.leverage,
+            realized_pnl: maker_position.realized_pnl,
+            liquidation_price: maker_position.liquidation_price,
+            timestamp,
+        });
+        
+        // Persist the maker accounts
+        maker_position.exit(&crate::ID)?;
+        maker_margin_account.exit(&crate::ID)?;
+    }
+    
+    // Settle the taker's PnL over all its fills, then charge its fee
+    settlement.bad_debt = margin_account.settle_pnl(position)?;
+    margin_account.collateral = margin_account.collateral
+        .checked_sub(settlement.taker_fee)
+        .ok_or(ErrorCode::InsufficientMargin)?;
+    
+    if let (Some(last_fill), true) = (fills.last(), settlement.realized_pnl != 0) {
+        emit!(PnlRealized {
+            market: market_key,
+            user: trade.user,
+            price: last_fill.price,
+            pnl: settlement.realized_pnl,
+            collateral: margin_account.collateral,
+            timestamp,
+        });
+    }
+    
+    Ok(settlement)
+}
+
+// Record a perp trade's price: sample the mark into the funding TWAPs and
+// check the circuit breaker against them
+fn record_perp_trade(
+    market: &mut Account<Market>,
+    mark_price: u64,
+    trade_price: u64,
+    oracle_price: u64,
+    timestamp: u64,
+) -> Result<()> {
+    market.last_trade_price = trade_price;
+    market.update_twaps(mark_price, oracle_price, timestamp)?;
+    check_circuit_breaker(market, timestamp)
+}
+
+// Trip the circuit breaker if the mark TWAP strays too far from the oracle TWAP
+fn check_circuit_breaker(market: &mut Account<Market>, timestamp: u64) -> Result<()> {
+    if market.check_mark_deviation()? {
+        let market_key = market.key();
+        emit!(CircuitBreakerTripped {
+            market: market_key,
+            mark_price: market.mark_price_twap,
+            oracle_price: market.oracle_price_twap,
+            max_mark_deviation: market.max_mark_deviation,
+            timestamp,
+        });
+        emit!(MarketStatusChanged {
+            market: market_key,
+            status: market.status,
+            timestamp,
+        });
+    }
+    
+    Ok(())
+}
+
+// Pay out of the collateral vault
+fn pay_from_collateral<'info>(
+    vaults: &PerpVaults<'info>,
+    destination: AccountInfo<'info>,
+    amount: u64,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    
+    let seeds = &[
+        b"collateral_signer".as_ref(),
+        &[vaults.collateral_signer_bump],
+    ];
+    let signer = &[&seeds[..]];
+    
+    token::transfer(
+        CpiContext::new_with_signer(
+            vaults.token_program.clone(),
+            Transfer {
+                from: vaults.collateral_vault.clone(),
+                to: destination,
+                authority: vaults.collateral_signer.clone(),
+            },
+            signer,
+        ),
+        amount,
+    )
+}
+
+// Move a fee already taken out of collateral to its destination, less the
+// insurance fund's share. Returns the amount paid to the destination.
+fn collect_perp_fee<'info>(
+    market: &mut Account<'info, Market>,
+    vaults: &PerpVaults<'info>,
+    destination: AccountInfo<'info>,
+    amount: u64,
+) -> Result<u64> {
+    let insurance_fee = math::bps_of(amount, market.insurance_fee_share)?;
+    pay_insurance_fund(market, vaults, insurance_fee)?;
+    
+    let fee = amount - insurance_fee;
+    pay_from_collateral(vaults, destination, fee)?;
+    
+    Ok(fee)
+}
+
+// Move an amount already taken out of collateral into the insurance fund
+fn pay_insurance_fund<'info>(
+    market: &mut Account<'info, Market>,
+    vaults: &PerpVaults<'info>,
+    amount: u64,
+) -> Result<()> {
+    pay_from_collateral(vaults, vaults.insurance_fund.clone(), amount)?;
+    market.insurance_fund_balance = market.insurance_fund_balance
+        .checked_add(amount)
+        .ok_or(ErrorCode::MathOverflow)?;
+    
+    Ok(())
+}
+
+// Emit events for makers the matching engine took off the book without
+// filling them
+fn emit_removed_makers(market: Pubkey, match_result: &MatchResult, timestamp: u64) {
+    for expired_order in match_result.expired_makers.iter() {
+        emit!(OrderExpired {
+            market,
+            order_id: expired_order.id,
+            client_id: expired_order.client_id,
+            user: expired_order.user,
+            side: expired_order.side,
+            price: expired_order.price,
+            remaining_size: expired_order.remaining_size,
+            expiry: expired_order.expiry,
+            timestamp,
+        });
+    }
+    
+    for (cancelled_order, cancelled_size) in match_result.cancelled_makers.iter() {
+        emit!(OrderCancelled {
+            market,
+            order_id: cancelled_order.id,
+            client_id: cancelled_order.client_id,
+            user: cancelled_order.user,
+            side: cancelled_order.side,
+            price: cancelled_order.price,
+            remaining_size: *cancelled_size,
+            reduce_only: cancelled_order.reduce_only,
+            timestamp,
+        });
+    }
+}
+
+// Find a market's price updates among the remaining accounts and price it
+// the same way as instructions that take them directly
+fn find_oracle_price<'info>(accounts: &'info [AccountInfo<'info>], market: &Account<Market>) -> Result<u64> {
+    let mut primary = None;
+    let mut secondary = None;
+    
+    for account_info in accounts {
+        if let Ok(price_update) = Account::<PriceUpdateV2>::try_from(account_info) {
+            let feed_id = price_update.price_message.feed_id;
+            if feed_id == market.oracle_feed_id && primary.is_none() {
+                primary = Some(price_update);
+            } else if feed_id == market.secondary_oracle_feed_id && secondary.is_none() {
+                secondary = Some(price_update);
+            }
+        }
+    }
+    
+    // The primary update must be passed even when it is stale
+    let primary = primary.ok_or(ErrorCode::InvalidPriceFeed)?;
+    Ok(get_oracle_price(&primary, secondary.as_ref(), market)?.price)
+}
+
+// Aggregate collateral, PnL and margin requirements over a margin account's
+// positions and resting orders. Every position other than `exclude` must be
+// passed in the remaining accounts along with its market's orderbook; the
+// market and its primary Pyth price update (plus its secondary one to fall
+// back on) are needed too unless the position is empty with no orders. The
+// excluded position is valued by the caller.
+fn cross_margin_summary<'info>(
+    margin_account: &MarginAccount,
+    accounts: &'info [AccountInfo<'info>],
+    exclude: Option<&Pubkey>,
+) -> Result<MarginSummary> {
+    let mut summary = MarginSummary::new(margin_account.collateral);
+    let timestamp = Clock::get()?.unix_timestamp as u64;
+    
+    for position_key in margin_account.positions.iter() {
+        if Some(position_key) == exclude {
+            continue;
+        }
+        
+        let position_info = accounts
+            .iter()
+            .find(|account_info| account_info.key == position_key)
+            .ok_or(ErrorCode::PositionNotFound)?;
+        let position = Account::<Position>::try_from(position_info)?;
+        
+        // Resting orders count towards the initial requirement, so the
+        // market's orderbook is always needed
+        let open_orders = accounts
+            .iter()
+            .find_map(|account_info| {
+                let orderbook = AccountLoader::<Orderbook>::try_from(account_info).ok()?;
+                let orderbook = orderbook.load().ok()?;
+                (orderbook.market == position.market).then(|| orderbook.open_orders(&margin_account.owner))
+            })
+            .ok_or(ErrorCode::InvalidOrderbook)?;
+        
+        // Empty positions without orders only carry their unsettled PnL
+        if position.is_empty() && open_orders.is_empty() {
+            summary.equity += position.realized_pnl as i128;
+            summary.withdrawable_equity += position.realized_pnl as i128;
+            continue;
+        }
+        
+        let market_info = accounts
+            .iter()
+            .find(|account_info| *account_info.key == position.market)
+            .ok_or(ErrorCode::InvalidParameters)?;
+        let market = Account::<Market>::try_from(market_info)?;
+        let price = find_oracle_price(accounts, &market)?;
+        
+        summary.add_position(&position, &market, price, &open_orders, timestamp)?;
+    }
+    
+    Ok(summary)
+}
+
+// A validated oracle price, scaled to 6 decimals
+#[derive(Clone, Copy, Debug)]
+pub struct OraclePrice {
+    pub price: u64,
+    pub confidence: u64,
+    pub publish_time: i64,
+    pub source: OracleSource,
+}
+
+// Price a market from its primary feed, falling back to the secondary feed
+// and then the book's mark TWAP. The primary update is always required; only a
+// stale or invalid primary price falls back, so a caller can't pick the
+// fallback by leaving it out. A secondary price passed alongside a working
+// primary must agree with it; fallback prices must agree with the oracle TWAP.
+fn get_oracle_price(
+    primary: &Account<PriceUpdateV2>,
+    secondary: Option<&Account<PriceUpdateV2>>,
+    market: &Account<Market>,
+) -> Result<OraclePrice> {
+    require!(primary.price_message.feed_id == market.oracle_feed_id, ErrorCode::InvalidPriceFeed);
+    
+    let primary_price = get_pyth_price(primary, market).ok();
+    let secondary_price = match secondary {
+        Some(price_update) if market.secondary_oracle_feed_id != [0; 32] => {
+            read_pyth_price(price_update, &market.secondary_oracle_feed_id, OracleSource::Secondary, market).ok()
+        },
+        _ => None,
+    };
+    
+    let oracle_price = match (primary_price, secondary_price) {
+        (Some(primary_price), Some(secondary_price)) => {
+            require!(
+                math::deviation_bps(secondary_price.price, primary_price.price)? <= market.max_oracle_deviation as u64,
+                ErrorCode::OracleDeviationTooLarge
+            );
+            primary_price
+        },
+        (Some(primary_price), None) => primary_price,
+        (None, Some(secondary_price)) => secondary_price,
+        (None, None) => {
+            // The mark TWAP only stands in while it is still current
+            let timestamp = Clock::get()?.unix_timestamp as u64;
+            require!(
+                market.book_twap_fallback
+                    && market.mark_price_twap > 0
+                    && timestamp.saturating_sub(market.last_twap_timestamp) <= market.funding_interval,
+                ErrorCode::InvalidPriceFeed
+            );
+            
+            OraclePrice {
+                price: market.mark_price_twap,
+                confidence: 0,
+                publish_time: market.last_twap_timestamp as i64,
+                source: OracleSource::BookTwap,
+            }
+        },
+    };
+    
+    if oracle_price.source != OracleSource::Primary {
+        // Fallbacks must not stray from where the oracle has been trading
+        if market.oracle_price_twap > 0 {
+            require!(
+                math::deviation_bps(oracle_price.price, market.oracle_price_twap)? <= market.max_oracle_deviation as u64,
+                ErrorCode::OracleDeviationTooLarge
+            );
+        }
+        
+        emit!(OracleFallbackUsed {
+            market: market.key(),
+            source: oracle_price.source,
+            price: oracle_price.price,
+            oracle_price_twap: market.oracle_price_twap,
+            timestamp: Clock::get()?.unix_timestamp as u64,
+        });
+    }
+    
+    Ok(oracle_price)
+}
+
+// Helper functions for Pyth price feed
+fn get_pyth_price(price_update: &Account<PriceUpdateV2>, market: &Account<Market>) -> Result<OraclePrice> {
+    read_pyth_price(price_update, &market.oracle_feed_id, OracleSource::Primary, market)
+}
+
+fn read_pyth_price(
+    price_update: &Account<PriceUpdateV2>,
+    feed_id: &[u8; 32],
+    source: OracleSource,
+    market: &Account<Market>,
+) -> Result<OraclePrice> {
+    // Maximum age check is now handled by get_price_no_older_than
+    let price = price_update.get_price_no_older_than(
+        &Clock::get()?,
+        market.max_oracle_age,
+        feed_id
+    ).map_err(|_| ErrorCode::InvalidPriceFeed)?;
+    
+    // Zero or negative prices are never valid for our markets
+    require!(price.price > 0, ErrorCode::InvalidOraclePrice);
+    
+    // Convert to 6 decimals (standard for our pricing)
+    let scaled_price = math::scale_price(price.price as u64, price.exponent)?;
+    let confidence = math::scale_price(price.conf, price.exponent)?;
+    require!(scaled_price > 0, ErrorCode::InvalidOraclePrice);
+    
+    // Reject prices the publishers disagree on too much
+    require!(
+        confidence <= math::bps_of(scaled_price, market.max_oracle_confidence)?,
+        ErrorCode::OracleConfidenceTooWide
+    );
+    
+    Ok(OraclePrice {
+        price: scaled_price,
+        confidence,
+        publish_time: price.publish_time,
+        source,
+    })
+}
+// Program implementation start
+#[program]
+pub mod omniliquid_clob {
+    use super::*;
+
+
+    pub fn initialize(
+        ctx: Context<Initialize>,
+        market_name: String,
+        market_symbol: String,
+        asset_id: String,
+        is_perpetual: bool,
+        settle_with_usdc: bool,
+        min_base_order_size: u64,
+        tick_size: u64,
+        taker_fee_bps: u16,
+        maker_rebate_bps: u16,
+        max_leverage: u16,
+        funding_interval: u64,
+        vault_signer_bump: u8,
+        registry: Pubkey,
+        oracle_feed_id_hex: String,
+        max_oracle_age: u64,
+    ) -> Result<()> {
+        // Validate parameters
+        require!(
+            taker_fee_bps <= 500 && maker_rebate_bps <= taker_fee_bps,
+            ErrorCode::InvalidParameters
+        );
+        
+        require!(
+            min_base_order_size > 0 && tick_size > 0,
+            ErrorCode::InvalidParameters
+        );
+        
+        require!(
+            market_name.len() <= 32 && market_symbol.len() <= 16 && asset_id.len() <= 16,
+            ErrorCode::InvalidParameters
+        );
+        
+        require!(max_leverage > 0 && max_leverage <= 10000, ErrorCode::InvalidParameters);
+        
+        // Parse oracle feed ID
+        let oracle_feed_id = get_feed_id_from_hex(&oracle_feed_id_hex)?;
+        
+        let market = &mut ctx.accounts.market;
+        let mut orderbook = ctx.accounts.orderbook.load_init()?;
+        
+        // Initialize market
+        market.authority = ctx.accounts.authority.key();
+        market.base_mint = ctx.accounts.base_mint.key();
+        market.quote_mint = ctx.accounts.quote_mint.key();
+        market.base_vault = ctx.accounts.base_vault.key();
+        market.quote_vault = ctx.accounts.quote_vault.key();
+        market.fee_vault = ctx.accounts.fee_vault.key();
+        market.accrued_fees = 0;
+        market.vault_signer_bump = vault_signer_bump;
+        market.registry = registry;
+        
+        // Set basic parameters
+        market.min_base_order_size = min_base_order_size;
+        market.tick_size = tick_size;
+        market.taker_fee_bps = taker_fee_bps;
+        market.maker_rebate_bps = maker_rebate_bps;
+        market.max_leverage = max_leverage;
+        
+        // Initialize order tracking
+        market.next_order_id = 1;
+        market.next_client_id = 1;
+        market.status = MarketStatus::Active;
+        
+        // Set market metadata
+        market.market_name = market_name.clone();
+        market.market_symbol = market_symbol.clone();
+        market.asset_id = asset_id.clone();
+        market.is_perpetual = is_perpetual;
+        market.settle_with_usdc = settle_with_usdc;
+        
+        // Initialize perpetual market data
+        let current_timestamp = Clock::get()?.unix_timestamp as u64;
+        market.last_funding_timestamp = current_timestamp;
+        market.last_oracle_price = 0;
+        market.oracle_price_offset = 0;
+        market.mark_price_twap = 0;
+        market.open_interest_long = 0;
+        market.open_interest_short = 0;
+        market.cumulative_funding_long = 0;
+        market.cumulative_funding_short = 0;
+        market.funding_interval = funding_interval;
+        market.funding_rate = 0;
+        market.last_funding_accrual = current_timestamp;
+        market.oracle_price_twap = 0;
+        market.last_mark_price = 0;
+        market.last_twap_timestamp = current_timestamp;
+        market.funding_period = funding_interval.saturating_mul(DEFAULT_FUNDING_PERIOD_INTERVALS);
+        market.max_funding_rate = DEFAULT_MAX_FUNDING_RATE;
+        
+        // Set oracle parameters
+        market.oracle_feed_id = oracle_feed_id;
+        market.max_oracle_age = max_oracle_age;
+        market.max_oracle_confidence = DEFAULT_MAX_ORACLE_CONFIDENCE;
+        market.secondary_oracle_feed_id = [0; 32];
+        market.book_twap_fallback = false;
+        market.max_oracle_deviation = DEFAULT_MAX_ORACLE_DEVIATION;
+        
+        // Set circuit breaker parameters
+        market.price_band = DEFAULT_PRICE_BAND;
+        market.max_slot_price_move = DEFAULT_MAX_SLOT_PRICE_MOVE;
+        market.max_mark_deviation = DEFAULT_MAX_MARK_DEVIATION;
+        market.last_trade_price = 0;
+        market.last_trade_slot = 0;
+        market.slot_open_price = 0;
+        market.trigger_order_fee = DEFAULT_TRIGGER_ORDER_FEE;
+        
+        // Set liquidation parameters
+        market.liquidation_close_factor = DEFAULT_LIQUIDATION_CLOSE_FACTOR;
+        market.liquidation_max_slippage = DEFAULT_LIQUIDATION_MAX_SLIPPAGE;
+        
+        // Set insurance fund parameters
+        market.insurance_fund = ctx.accounts.insurance_fund.key();
+        market.insurance_fund_balance = 0;
+        market.insurance_fee_share = DEFAULT_INSURANCE_FEE_SHARE;
+        market.socialized_loss_index_long = 0;
+        market.socialized_loss_index_short = 0;
+        
+        // Initialize orderbook
+        orderbook.init(market.key());
+        
+        emit!(MarketCreated {
+            market: market.key(),
+            base_mint: market.base_mint,
+            quote_mint: market.quote_mint,
+            market_name,
             market_symbol,
             asset_id,
             is_perpetual,
@@ -940,1135 +2950,2467 @@ pub mod omniliquid_clob {
         Ok(())
     }
 
-    pub fn change_market_status(
-        ctx: Context<ChangeMarketStatus>, 
-        new_status: MarketStatus
+    pub fn change_market_status(
+        ctx: Context<ChangeMarketStatus>, 
+        new_status: MarketStatus
+    ) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        
+        // Reactivating a market, including one paused by the circuit breaker,
+        // is a governance decision like collecting fees. Any route back to
+        // Active counts, so the authority can't close a paused market and
+        // reopen it around the breaker.
+        if market.status != MarketStatus::Active && new_status == MarketStatus::Active {
+            require!(ctx.accounts.authority.key() == ctx.accounts.registry.gov, ErrorCode::NotGov);
+        }
+        
+        market.status = new_status;
+        
+        emit!(MarketStatusChanged {
+            market: market.key(),
+            status: new_status,
+            timestamp: Clock::get()?.unix_timestamp as u64,
+        });
+        
+        Ok(())
+    }
+
+    pub fn set_liquidation_params(
+        ctx: Context<UpdateMarketParams>,
+        close_factor: u16,
+        max_slippage: u16
+    ) -> Result<()> {
+        require!(
+            close_factor > 0 && close_factor as u64 <= math::BPS_DENOMINATOR,
+            ErrorCode::InvalidParameters
+        );
+        require!((max_slippage as u64) < math::BPS_DENOMINATOR, ErrorCode::InvalidParameters);
+        
+        let market = &mut ctx.accounts.market;
+        market.liquidation_close_factor = close_factor;
+        market.liquidation_max_slippage = max_slippage;
+        
+        emit!(LiquidationParamsUpdated {
+            market: market.key(),
+            close_factor,
+            max_slippage,
+            timestamp: Clock::get()?.unix_timestamp as u64,
+        });
+        
+        Ok(())
+    }
+
+    pub fn set_oracle_params(
+        ctx: Context<UpdateMarketParams>,
+        max_oracle_age: u64,
+        max_oracle_confidence: u16
+    ) -> Result<()> {
+        require!(max_oracle_age > 0, ErrorCode::InvalidParameters);
+        require!(max_oracle_confidence as u64 <= math::BPS_DENOMINATOR, ErrorCode::InvalidParameters);
+        
+        let market = &mut ctx.accounts.market;
+        market.max_oracle_age = max_oracle_age;
+        market.max_oracle_confidence = max_oracle_confidence;
+        
+        emit!(OracleParamsUpdated {
+            market: market.key(),
+            max_oracle_age,
+            max_oracle_confidence,
+            timestamp: Clock::get()?.unix_timestamp as u64,
+        });
+        
+        Ok(())
+    }
+
+    pub fn set_circuit_breaker_params(
+        ctx: Context<UpdateMarketParams>,
+        price_band: u16,
+        max_slot_price_move: u16,
+        max_mark_deviation: u16
+    ) -> Result<()> {
+        require!(
+            price_band > 0 && price_band as u64 <= math::BPS_DENOMINATOR,
+            ErrorCode::InvalidParameters
+        );
+        require!(
+            max_slot_price_move as u64 <= math::BPS_DENOMINATOR
+                && max_mark_deviation as u64 <= math::BPS_DENOMINATOR,
+            ErrorCode::InvalidParameters
+        );
+        
+        let market = &mut ctx.accounts.market;
+        market.price_band = price_band;
+        market.max_slot_price_move = max_slot_price_move;
+        market.max_mark_deviation = max_mark_deviation;
+        
+        emit!(CircuitBreakerParamsUpdated {
+            market: market.key(),
+            price_band,
+            max_slot_price_move,
+            max_mark_deviation,
+            timestamp: Clock::get()?.unix_timestamp as u64,
+        });
+        
+        Ok(())
+    }
+
+    pub fn set_oracle_fallback(
+        ctx: Context<UpdateMarketParams>,
+        secondary_oracle_feed_id_hex: Option<String>,
+        book_twap_fallback: bool,
+        max_oracle_deviation: u16
+    ) -> Result<()> {
+        require!(
+            max_oracle_deviation > 0 && max_oracle_deviation as u64 <= math::BPS_DENOMINATOR,
+            ErrorCode::InvalidParameters
+        );
+        
+        let secondary_oracle_feed_id = match secondary_oracle_feed_id_hex {
+            Some(hex) => get_feed_id_from_hex(&hex)?,
+            None => [0; 32],
+        };
+        
+        let market = &mut ctx.accounts.market;
+        require!(secondary_oracle_feed_id != market.oracle_feed_id, ErrorCode::InvalidParameters);
+        market.secondary_oracle_feed_id = secondary_oracle_feed_id;
+        market.book_twap_fallback = book_twap_fallback;
+        market.max_oracle_deviation = max_oracle_deviation;
+        
+        emit!(OracleFallbackUpdated {
+            market: market.key(),
+            secondary_oracle_feed_id,
+            book_twap_fallback,
+            max_oracle_deviation,
+            timestamp: Clock::get()?.unix_timestamp as u64,
+        });
+        
+        Ok(())
+    }
+
+    pub fn set_funding_params(
+        ctx: Context<UpdateMarketParams>,
+        funding_period: u64,
+        max_funding_rate: u16
+    ) -> Result<()> {
+        require!(funding_period > 0, ErrorCode::InvalidParameters);
+        require!(max_funding_rate as u64 <= math::BPS_DENOMINATOR, ErrorCode::InvalidParameters);
+        
+        let market = &mut ctx.accounts.market;
+        market.funding_period = funding_period;
+        market.max_funding_rate = max_funding_rate;
+        
+        emit!(FundingParamsUpdated {
+            market: market.key(),
+            funding_period,
+            max_funding_rate,
+            timestamp: Clock::get()?.unix_timestamp as u64,
+        });
+        
+        Ok(())
+    }
+
+    pub fn set_trigger_order_fee(ctx: Context<UpdateMarketParams>, trigger_order_fee: u64) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        market.trigger_order_fee = trigger_order_fee;
+        
+        emit!(TriggerOrderFeeUpdated {
+            market: market.key(),
+            trigger_order_fee,
+            timestamp: Clock::get()?.unix_timestamp as u64,
+        });
+        
+        Ok(())
+    }
+
+    pub fn set_insurance_fee_share(
+        ctx: Context<UpdateMarketParams>,
+        insurance_fee_share: u16
+    ) -> Result<()> {
+        require!(insurance_fee_share as u64 <= math::BPS_DENOMINATOR, ErrorCode::InvalidParameters);
+        
+        let market = &mut ctx.accounts.market;
+        market.insurance_fee_share = insurance_fee_share;
+        
+        emit!(InsuranceParamsUpdated {
+            market: market.key(),
+            insurance_fee_share,
+            timestamp: Clock::get()?.unix_timestamp as u64,
+        });
+        
+        Ok(())
+    }
+    
+    pub fn deposit_insurance_fund(ctx: Context<DepositInsuranceFund>, amount: u64) -> Result<()> {
+        require!(amount > 0, ErrorCode::InvalidParameters);
+        
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.depositor_quote_account.to_account_info(),
+                    to: ctx.accounts.insurance_fund.to_account_info(),
+                    authority: ctx.accounts.depositor.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+        
+        let market = &mut ctx.accounts.market;
+        market.insurance_fund_balance = market.insurance_fund_balance
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        
+        emit!(InsuranceFundDeposited {
+            market: market.key(),
+            depositor: ctx.accounts.depositor.key(),
+            amount,
+            balance: market.insurance_fund_balance,
+            timestamp: Clock::get()?.unix_timestamp as u64,
+        });
+        
+        Ok(())
+    }
+
+    pub fn sync_asset_params(ctx: Context<SyncAssetParams>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        market.sync_asset_params(&ctx.accounts.registry)?;
+        
+        emit!(AssetParamsSynced {
+            market: market.key(),
+            maintenance_margin_ratio: market.maintenance_margin_ratio,
+            liquidation_fee: market.liquidation_fee,
+            max_leverage: market.asset_max_leverage,
+            funding_rate_multiplier: market.funding_rate_multiplier,
+            active: market.asset_active,
+            timestamp: Clock::get()?.unix_timestamp as u64,
+        });
+        
+        Ok(())
+    }
+
+    pub fn collect_fees(ctx: Context<CollectFees>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let amount = market.accrued_fees;
+        
+        require!(amount > 0, ErrorCode::InvalidParameters);
+        
+        // Create PDA signer seeds
+        let market_key = market.key();
+        let seeds = &[
+            b"vault_signer".as_ref(),
+            market_key.as_ref(),
+            &[market.vault_signer_bump],
+        ];
+        let signer = &[&seeds[..]];
+        
+        // Sweep accrued fees to the destination account
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.fee_vault.to_account_info(),
+                    to: ctx.accounts.destination.to_account_info(),
+                    authority: ctx.accounts.vault_signer.to_account_info(),
+                },
+                signer,
+            ),
+            amount,
+        )?;
+        
+        market.accrued_fees = 0;
+        
+        emit!(FeesCollected {
+            market: market_key,
+            destination: ctx.accounts.destination.key(),
+            amount,
+            timestamp: Clock::get()?.unix_timestamp as u64,
+        });
+        
+        Ok(())
+    }
+
+    pub fn update_funding_rate(ctx: Context<UpdateFundingRate>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let orderbook = ctx.accounts.orderbook.load()?;
+        
+        // Verify this is a perpetual market
+        require!(market.is_perpetual, ErrorCode::NotPerpetualMarket);
+        
+        // Check if enough time has passed since last update
+        let current_time = Clock::get()?.unix_timestamp as u64;
+        require!(
+            current_time >= market.last_funding_timestamp + market.funding_interval,
+            ErrorCode::FundingRateTooSoon
+        );
+        
+        // Get oracle price from Pyth
+        let oracle_price = get_oracle_price(&ctx.accounts.pyth_price_feed, ctx.accounts.fallback_price_feed.as_ref(), market)?.price;
+        
+        // Sample the mark price from the orderbook into the TWAPs
+        let mark_price = match orderbook.mid_price(current_time) {
+            Some(price) => price,
+            None => oracle_price, // If orderbook is empty, use oracle price
+        };
+        market.update_twaps(mark_price, oracle_price, current_time)?;
+        
+        // Trip the circuit breaker if the mark TWAP strays too far from the oracle
+        check_circuit_breaker(market, current_time)?;
+        
+        // Premium of the mark TWAP over the oracle TWAP, paid off over the
+        // funding period, scaled by the asset's multiplier and capped
+        market.sync_asset_params(&ctx.accounts.registry)?;
+        let premium_index = math::premium_index(market.mark_price_twap, market.oracle_price_twap)?;
+        let funding_rate = math::funding_rate(
+            premium_index,
+            market.funding_interval,
+            market.funding_period,
+            market.funding_rate_multiplier,
+            market.max_funding_rate,
+        )?;
+        
+        market.last_funding_timestamp = current_time;
+        
+        // Accrue the previous rate up to now, then charge the new one going
+        // forward. Positions settle against the indexes when next touched.
+        market.accrue_funding(current_time)?;
+        market.funding_rate = funding_rate;
+        
+        emit!(FundingRateUpdated {
+            market: market.key(),
+            oracle_price,
+            mark_price,
+            oracle_price_twap: market.oracle_price_twap,
+            mark_price_twap: market.mark_price_twap,
+            premium_index,
+            funding_rate,
+            timestamp: current_time,
+        });
+        
+        Ok(())
+    }
+
+    pub fn place_order<'info>(
+        ctx: Context<'_, '_, 'info, 'info, PlaceOrder<'info>>,
+        client_id: Option<u64>,
+        side: Side,
+        price: u64,
+        size: u64,
+        order_type: OrderType,
+        self_trade_behavior: SelfTradeBehavior,
+        reduce_only: bool,
+        post_only: bool,
+        leverage: Option<u16>,
+    ) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let mut orderbook = ctx.accounts.orderbook.load_mut()?;
+        let user_key = ctx.accounts.user.key();
+        let timestamp = Clock::get()?.unix_timestamp as u64;
+        
+        // Validate market is active
+        require!(market.status == MarketStatus::Active, ErrorCode::MarketInactive);
+        
+        // Validate order size
+        require!(size >= market.min_base_order_size, ErrorCode::OrderSizeTooSmall);
+        
+        // Good-till-time orders must expire in the future
+        let (time_in_force, expiry) = match order_type {
+            OrderType::GoodTillTime { expiry } => {
+                require!(expiry > timestamp, ErrorCode::InvalidExpiry);
+                (TIME_IN_FORCE_GOOD_TILL_TIME, expiry)
+            },
+            _ => (TIME_IN_FORCE_GOOD_TILL_CANCEL, 0),
+        };
+        
+        // Perp orders are always checked against a validated oracle price.
+        // Spot orders are fully escrowed and must not pass price updates, so
+        // which accounts an order needs never depends on what the caller omits.
+        let oracle_price = match (market.is_perpetual, ctx.accounts.pyth_price_feed.as_ref()) {
+            (true, Some(pyth_price_feed)) => {
+                Some(get_oracle_price(pyth_price_feed, ctx.accounts.fallback_price_feed.as_ref(), market)?.price)
+            },
+            (false, None) => {
+                require!(ctx.accounts.fallback_price_feed.is_none(), ErrorCode::InvalidPriceFeed);
+                None
+            },
+            _ => return Err(ErrorCode::InvalidPriceFeed.into()),
+        };
+        
+        // Validate tick size for limit orders
+        if order_type != OrderType::Market {
+            require!(price % market.tick_size == 0, ErrorCode::InvalidTickSize);
+            
+            // Validate price is within reasonable range of current price if perpmarket
+            if let Some(oracle_price) = oracle_price {
+                market.check_price_band(price, oracle_price)?;
+            }
+        }
+        
+        // Check for reduce_only constraints
+        if reduce_only {
+            if let Some(position) = ctx.accounts.position.as_ref() {
+                match side {
+                    Side::Bid => {
+                        // Can only reduce an ASK position
+                        require!(
+                            position.side == Side::Ask && position.size > 0,
+                            ErrorCode::InvalidReduceOnlyOrder
+                        );
+                        
+                        // Cannot exceed position size
+                        require!(
+                            size <= position.size,
+                            ErrorCode::InvalidReduceOnlySize
+                        );
+                    },
+                    Side::Ask => {
+                        // Can only reduce a BID position
+                        require!(
+                            position.side == Side::Bid && position.size > 0,
+                            ErrorCode::InvalidReduceOnlyOrder
+                        );
+                        
+                        // Cannot exceed position size
+                        require!(
+                            size <= position.size,
+                            ErrorCode::InvalidReduceOnlySize
+                        );
+                    }
+                }
+            } else {
+                return Err(ErrorCode::NoPositionToReduce.into());
+            }
+        }
+        
+        // For perpetual markets, check if the position needs to be created
+        // or if leverage needs to be set
+        if market.is_perpetual {
+            require!(ctx.accounts.position.is_some(), ErrorCode::PositionNotFound);
+            require!(
+                ctx.accounts.margin_account.is_some()
+                    && ctx.accounts.collateral_vault.is_some()
+                    && ctx.accounts.collateral_signer.is_some(),
+                ErrorCode::InvalidMarginAccount
+            );
+            
+            // Get asset parameters from registry
+            market.sync_asset_params(&ctx.accounts.registry)?;
+            
+            // Bring funding up to date before valuing the position
+            market.accrue_funding(timestamp)?;
+            require!(market.asset_active, ErrorCode::AssetNotAvailable);
+            
+            let max_leverage = std::cmp::min(market.max_leverage, market.asset_max_leverage);
+            
+            // If this is a new position, check if leverage is provided
+            if let Some(lev) = leverage {
+                require!(
+                    lev > 0 && lev <= max_leverage,
+                    ErrorCode::ExceedsMaxLeverage
+                );
+                
+                // Update leverage on the position
+                let position = ctx.accounts.position.as_mut().ok_or(ErrorCode::PositionNotFound)?;
+                position.leverage = lev;
+            }
+            
+            // Orders that can add exposure are checked against the initial
+            // requirement once they have filled and rested, below
+            if !reduce_only {
+                let position = ctx.accounts.position.as_ref().ok_or(ErrorCode::PositionNotFound)?;
+                require!(
+                    position.leverage > 0 && position.leverage <= max_leverage,
+                    ErrorCode::ExceedsMaxLeverage
+                );
+            }
+        }
+        
+        // Generate order ID and client ID
+        let order_id = market.next_order_id;
+        market.next_order_id += 1;
+        
+        let client_order_id = client_id.unwrap_or_else(|| {
+            let id = market.next_client_id;
+            market.next_client_id += 1;
+            id
+        });
+        
+        // Create the order
+        let new_order = Order::new(
+            order_id,
+            client_order_id,
+            user_key,
+            side,
+            price,
+            size,
+            time_in_force,
+            expiry,
+            reduce_only,
+            post_only,
+        );
+        
+        let market_key = market.key();
+        
+        // Spot markets settle in base and quote tokens, so the user needs both accounts
+        if !market.is_perpetual {
+            require!(ctx.accounts.user_base_account.is_some(), ErrorCode::InvalidTokenAccount);
+        }
+        
+        // PDA signer seeds for releasing escrowed tokens
+        let vault_signer_bump = [market.vault_signer_bump];
+        let seeds = &[
+            b"vault_signer".as_ref(),
+            market_key.as_ref(),
+            &vault_signer_bump,
+        ];
+        let signer = &[&seeds[..]];
+        
+        // Expired orders stay on the book until pruned, and matching only
+        // prunes the side it takes from. Clear the top of the order's own
+        // side, and of the other side for post-only orders that don't match,
+        // so price reads only ever walk past a few.
+        let mut pruned_orders = orderbook.prune_expired(side, timestamp)?;
+        if order_type == OrderType::PostOnly {
+            let opposite_side = match side {
+                Side::Bid => Side::Ask,
+                Side::Ask => Side::Bid,
+            };
+            pruned_orders.extend(orderbook.prune_expired(opposite_side, timestamp)?);
+        }
+        
+        // Post-only orders must not take liquidity
+        if order_type == OrderType::PostOnly {
+            // Check if the order would match immediately
+            let would_match = match side {
+                Side::Bid => orderbook.best_ask_price(timestamp).is_some_and(|ask_price| ask_price <= price),
+                Side::Ask => orderbook.best_bid_price(timestamp).is_some_and(|bid_price| bid_price >= price),
+            };
+            
+            // If the order would match, reject it
+            require!(!would_match, ErrorCode::PostOnlyWouldMatch);
+        }
+        
+        // Market orders take any price within the slot's allowed move, and
+        // every other order that matches stops at its limit or that move,
+        // whichever comes first. What's left of a limit past the move rests
+        // at the move, so it can't cross what it didn't fill.
+        market.start_slot(Clock::get()?.slot);
+        let limit_price = match order_type {
+            OrderType::Market => market.slot_limited_price(side, None)?,
+            OrderType::PostOnly => Some(price),
+            _ => market.slot_limited_price(side, Some(price))?,
+        };
+        let rest_price = limit_price.unwrap_or(price);
+        
+        // Match against the opposite side of the book
+        let mut match_result = if order_type == OrderType::PostOnly {
+            MatchResult::default()
+        } else {
+            orderbook.match_order(&user_key, side, limit_price, size, self_trade_behavior, timestamp)?
+        };
+        match_result.expired_makers.extend(pruned_orders);
+        
+        // Fill-or-kill orders revert unless they fill in full
+        if order_type == OrderType::FillOrKill {
+            require!(match_result.filled_size == size, ErrorCode::FillOrKillNotFilled);
+        }
+        
+        // Orders pruned past their expiry, from either side, and makers
+        // removed by self-trade prevention
+        emit_removed_makers(market_key, &match_result, timestamp);
+        
+        // Spot escrow of the removed orders goes back to their owners
+        if !market.is_perpetual {
+            // Expired orders' escrow goes to their claimable balances, like
+            // fill proceeds, so the taker needs none of their token accounts
+            for expired_order in match_result.expired_makers.iter() {
+                let (base_amount, quote_amount) = match expired_order.side {
+                    Side::Bid => (0, math::notional(expired_order.remaining_size, expired_order.price)?),
+                    Side::Ask => (expired_order.remaining_size, 0),
+                };
+                credit_claimable(ctx.remaining_accounts, &market_key, &expired_order.user, base_amount, quote_amount)?;
+            }
+            
+            // Self-trade prevention only removes the taker's own orders, so
+            // their escrow goes straight back to its accounts
+            for (cancelled_order, cancelled_size) in match_result.cancelled_makers.iter() {
+                match cancelled_order.side {
+                    Side::Bid => {
+                        let quote_amount = math::notional(*cancelled_size, cancelled_order.price)?;
+                        if quote_amount > 0 {
+                            token::transfer(
+                                CpiContext::new_with_signer(
+                                    ctx.accounts.token_program.to_account_info(),
+                                    Transfer {
+                                        from: ctx.accounts.quote_vault.to_account_info(),
+                                        to: ctx.accounts.user_quote_account.to_account_info(),
+                                        authority: ctx.accounts.vault_signer.to_account_info(),
+                                    },
+                                    signer,
+                                ),
+                                quote_amount,
+                            )?;
+                        }
+                    },
+                    Side::Ask => {
+                        token::transfer(
+                            CpiContext::new_with_signer(
+                                ctx.accounts.token_program.to_account_info(),
+                                Transfer {
+                                    from: ctx.accounts.base_vault.to_account_info(),
+                                    to: ctx.accounts.user_base_account.as_ref().unwrap().to_account_info(),
+                                    authority: ctx.accounts.vault_signer.to_account_info(),
+                                },
+                                signer,
+                            ),
+                            *cancelled_size,
+                        )?;
+                    }
+                }
+            }
+        }
+        
+        // Settle fills
+        let mut filled_size = 0;
+        if market.is_perpetual {
+            let vaults = PerpVaults {
+                token_program: ctx.accounts.token_program.to_account_info(),
+                collateral_vault: ctx.accounts.collateral_vault.as_ref().ok_or(ErrorCode::InvalidMarginAccount)?.to_account_info(),
+                collateral_signer: ctx.accounts.collateral_signer.as_ref().ok_or(ErrorCode::InvalidMarginAccount)?.to_account_info(),
+                collateral_signer_bump: ctx.bumps.collateral_signer.ok_or(ErrorCode::InvalidMarginAccount)?,
+                insurance_fund: ctx.accounts.insurance_fund.to_account_info(),
+                vault_signer: ctx.accounts.vault_signer.to_account_info(),
+            };
+            let trade = PerpTrade {
+                user: user_key,
+                side,
+                order_id,
+                client_id: client_order_id,
+                size,
+                pays_fees: true,
+                timestamp,
+            };
+            let position = ctx.accounts.position.as_mut().ok_or(ErrorCode::PositionNotFound)?;
+            let margin_account = ctx.accounts.margin_account.as_mut().ok_or(ErrorCode::InvalidMarginAccount)?;
+            
+            let settlement = settle_perp_fills(
+                market,
+                &vaults,
+                ctx.remaining_accounts,
+                &trade,
+                position,
+                margin_account,
+                &match_result.fills,
+            )?;
+            filled_size = settlement.filled_size;
+            
+            // Takers can't trade into losses their collateral doesn't cover,
+            // which would let a reduce-only close write them off
+            require!(settlement.bad_debt == 0, ErrorCode::InsufficientMargin);
+            
+            if let (Some(last_fill), Some(oracle_price)) = (match_result.fills.last(), oracle_price) {
+                let mark_price = orderbook.mid_price(timestamp).unwrap_or(last_fill.price);
+                record_perp_trade(market, mark_price, last_fill.price, oracle_price, timestamp)?;
+            }
+            
+            // Move the protocol's share of fees into the fee vault
+            if settlement.protocol_fee > 0 {
+                let fee = collect_perp_fee(
+                    market,
+                    &vaults,
+                    ctx.accounts.fee_vault.to_account_info(),
+                    settlement.protocol_fee,
+                )?;
+                market.accrued_fees = market.accrued_fees.checked_add(fee).ok_or(ErrorCode::MathOverflow)?;
+            }
+            
+            if !match_result.fills.is_empty() {
+                emit!(PositionUpdated {
+                    market: market_key,
+                    user: user_key,
+                    side: position.side,
+                    size: position.size,
+                    collateral: margin_account.collateral,
+                    entry_price: position.entry_price,
+                    leverage: position.leverage,
+                    realized_pnl: position.realized_pnl,
+                    liquidation_price: position.liquidation_price,
+                    timestamp,
+                });
+            }
+        } else {
+            let user_base_account = ctx.accounts.user_base_account.as_ref().unwrap();
+            let mut protocol_fee = 0;
+            let mut base_traded = 0;
+            let mut quote_traded = 0;
+            let mut maker_rebates = 0;
+            
+            for fill in match_result.fills.iter() {
+                filled_size += fill.size;
+                
+                // Calculate quote amount and fees
+                let quote_amount = math::notional(fill.size, fill.price)?;
+                let taker_fee = math::bps_of(quote_amount, market.taker_fee_bps)?;
+                let maker_rebate = math::bps_of(quote_amount, market.maker_rebate_bps)?;
+                protocol_fee += taker_fee - maker_rebate;
+                base_traded += fill.size;
+                quote_traded += quote_amount;
+                maker_rebates += maker_rebate;
+                
+                // Makers are credited to their claimable balances and claim
+                // later, so a maker can't block fills by closing or freezing
+                // its token accounts
+                let (base_amount, quote_amount) = match side {
+                    Side::Bid => (0, quote_amount + maker_rebate),
+                    Side::Ask => (fill.size, maker_rebate),
+                };
+                credit_claimable(ctx.remaining_accounts, &market_key, &fill.maker, base_amount, quote_amount)?;
+                
+                emit!(OrderMatched {
+                    market: market_key,
+                    order_id,
+                    maker_order_id: fill.maker_order_id,
+                    client_id: client_order_id,
+                    maker_client_id: fill.maker_client_id,
+                    user: user_key,
+                    maker: fill.maker,
+                    side,
+                    price: fill.price,
+                    size: fill.size,
+                    quote_amount,
+                    taker_fee,
+                    maker_rebate,
+                    remaining_size: size - filled_size,
+                    timestamp,
+                });
+            }
+            
+            // Swap the taker's side of the fills for the makers' escrow. The
+            // taker pays the maker rebates on top of the quote it trades, or
+            // leaves them in the vault out of the quote it receives.
+            let (pay_account, pay_vault, pay_amount, receive_vault, receive_account, receive_amount) = match side {
+                Side::Bid => (
+                    ctx.accounts.user_quote_account.to_account_info(),
+                    ctx.accounts.quote_vault.to_account_info(),
+                    quote_traded + maker_rebates,
+                    ctx.accounts.base_vault.to_account_info(),
+                    user_base_account.to_account_info(),
+                    base_traded,
+                ),
+                Side::Ask => (
+                    user_base_account.to_account_info(),
+                    ctx.accounts.base_vault.to_account_info(),
+                    base_traded,
+                    ctx.accounts.quote_vault.to_account_info(),
+                    ctx.accounts.user_quote_account.to_account_info(),
+                    quote_traded - maker_rebates,
+                ),
+            };
+            
+            if pay_amount > 0 {
+                token::transfer(
+                    CpiContext::new(
+                        ctx.accounts.token_program.to_account_info(),
+                        Transfer {
+                            from: pay_account,
+                            to: pay_vault,
+                            authority: ctx.accounts.user.to_account_info(),
+                        },
+                    ),
+                    pay_amount,
+                )?;
+            }
+            
+            if receive_amount > 0 {
+                token::transfer(
+                    CpiContext::new_with_signer(
+                        ctx.accounts.token_program.to_account_info(),
+                        Transfer {
+                            from: receive_vault,
+                            to: receive_account,
+                            authority: ctx.accounts.vault_signer.to_account_info(),
+                        },
+                        signer,
+                    ),
+                    receive_amount,
+                )?;
+            }
+            
+            if let Some(last_fill) = match_result.fills.last() {
+                market.last_trade_price = last_fill.price;
+            }
+            
+            // Move the protocol's share of fees into the fee vault
+            if protocol_fee > 0 {
+                token::transfer(
+                    CpiContext::new(
+                        ctx.accounts.token_program.to_account_info(),
+                        Transfer {
+                            from: ctx.accounts.user_quote_account.to_account_info(),
+                            to: ctx.accounts.fee_vault.to_account_info(),
+                            authority: ctx.accounts.user.to_account_info(),
+                        },
+                    ),
+                    protocol_fee,
+                )?;
+                
+                market.accrued_fees = market.accrued_fees.checked_add(protocol_fee).ok_or(ErrorCode::MathOverflow)?;
+            }
+        }
+        
+        // Size that is still open after matching and self-trade prevention
+        let remaining_size = size - match_result.filled_size - match_result.decremented_size;
+        
+        match order_type {
+            OrderType::Market => {
+                // Market orders should have at least some fill
+                require!(filled_size > 0, ErrorCode::OrderNotFound);
+            },
+            OrderType::ImmediateOrCancel => {
+                // IOC orders should have at least some fill
+                require!(filled_size > 0, ErrorCode::OrderNotFound);
+                
+                // IOC orders do not get added to the book even if partially filled
+            },
+            OrderType::FillOrKill => {
+                // Fully filled above, so nothing is left to rest
+            },
+            OrderType::Limit | OrderType::PostOnly | OrderType::GoodTillTime { .. } => {
+                // If not fully filled, add remainder to book
+                if remaining_size > 0 && !match_result.taker_cancelled {
+                    let mut remaining_order = new_order;
+                    remaining_order.price = rest_price;
+                    remaining_order.remaining_size = remaining_size;
+                    
+                    // Lock the resting order's funds for spot markets. Its
+                    // fills are credited to the user's claimable balances,
+                    // which must exist before it can rest.
+                    if !market.is_perpetual {
+                        require!(ctx.accounts.claimable_balances.is_some(), ErrorCode::ClaimableBalancesNotFound);
+                        
+                        let (from, to, amount) = match side {
+                            Side::Bid => (
+                                ctx.accounts.user_quote_account.to_account_info(),
+                                ctx.accounts.quote_vault.to_account_info(),
+                                math::notional(remaining_size, rest_price)?,
+                            ),
+                            Side::Ask => (
+                                ctx.accounts.user_base_account.as_ref().unwrap().to_account_info(),
+                                ctx.accounts.base_vault.to_account_info(),
+                                remaining_size,
+                            ),
+                        };
+                        
+                        if amount > 0 {
+                            token::transfer(
+                                CpiContext::new(
+                                    ctx.accounts.token_program.to_account_info(),
+                                    Transfer {
+                                        from,
+                                        to,
+                                        authority: ctx.accounts.user.to_account_info(),
+                                    },
+                                ),
+                                amount,
+                            )?;
+                        }
+                    }
+                    
+                    if side == Side::Bid {
+                        orderbook.insert_order(&remaining_order)?;
+                        
+                        emit!(BidOrderAdded {
+                            market: market_key,
+                            order_id,
+                            client_id: client_order_id,
+                            user: user_key,
+                            price: rest_price,
+                            size: remaining_size,
+                            reduce_only,
+                            post_only,
+                            timestamp,
+                        });
+                    } else {
+                        orderbook.insert_order(&remaining_order)?;
+                        
+                        emit!(AskOrderAdded {
+                            market: market_key,
+                            order_id,
+                            client_id: client_order_id,
+                            user: user_key,
+                            price: rest_price,
+                            size: remaining_size,
+                            reduce_only,
+                            post_only,
+                            timestamp,
+                        });
+                    }
+                }
+            }
+        }
+        
+        // Check initial margin for orders that can add exposure against the
+        // account as the order left it: fills booked at their prices less the
+        // taker fee, and the remainder resting. The position is priced at the
+        // oracle and orders at their limit or the oracle, whichever is higher,
+        // so neither a fill nor a limit far from the market can understate
+        // the requirement or inflate the position's unrealized PnL.
+        if market.is_perpetual && !reduce_only {
+            let position = ctx.accounts.position.as_ref().ok_or(ErrorCode::PositionNotFound)?;
+            let margin_account = ctx.accounts.margin_account.as_ref().ok_or(ErrorCode::InvalidMarginAccount)?;
+            let oracle_price = oracle_price.ok_or(ErrorCode::InvalidPriceFeed)?;
+            let open_orders = orderbook.open_orders(&user_key);
+            
+            // This market's requirement on top of the user's other positions
+            let mut summary = cross_margin_summary(margin_account, ctx.remaining_accounts, Some(&position.key()))?;
+            summary.add_position(position, market, oracle_price, &open_orders, timestamp)?;
+            
+            require!(summary.equity >= summary.initial_margin as i128, ErrorCode::InsufficientMargin);
+        }
+        
+        Ok(())
+    }
+
+    pub fn cancel_order(
+        ctx: Context<CancelOrder>, 
+        order_id: u64, 
+        side: Side,
+        price: u64
     ) -> Result<()> {
-        let market = &mut ctx.accounts.market;
-        market.status = new_status;
+        let market = &ctx.accounts.market;
+        let mut orderbook = ctx.accounts.orderbook.load_mut()?;
+        let user_key = ctx.accounts.user.key();
+        let timestamp = Clock::get()?.unix_timestamp as u64;
         
-        emit!(MarketStatusChanged {
+        // Remove the order from the orderbook
+        let order = orderbook.cancel_order(side, price, order_id, &user_key)?;
+        let remaining_size = order.remaining_size;
+        
+        // For spot markets, return locked tokens
+        if !market.is_perpetual {
+            let (user_account, vault, amount) = match side {
+                Side::Bid => (
+                    ctx.accounts.user_quote_account.as_ref(),
+                    ctx.accounts.quote_vault.as_ref(),
+                    math::notional(remaining_size, price)?,
+                ),
+                Side::Ask => (
+                    ctx.accounts.user_base_account.as_ref(),
+                    ctx.accounts.base_vault.as_ref(),
+                    remaining_size,
+                ),
+            };
+            require!(
+                user_account.is_some() && vault.is_some() && ctx.accounts.vault_signer.is_some(),
+                ErrorCode::InvalidTokenAccount
+            );
+            
+            if amount > 0 {
+                // Create PDA signer seeds
+                let market_key = market.key();
+                let seeds = &[
+                    b"vault_signer".as_ref(),
+                    market_key.as_ref(),
+                    &[market.vault_signer_bump],
+                ];
+                let signer = &[&seeds[..]];
+                
+                token::transfer(
+                    CpiContext::new_with_signer(
+                        ctx.accounts.token_program.to_account_info(),
+                        Transfer {
+                            from: vault.unwrap().to_account_info(),
+                            to: user_account.unwrap().to_account_info(),
+                            authority: ctx.accounts.vault_signer.as_ref().unwrap().to_account_info(),
+                        },
+                        signer,
+                    ),
+                    amount,
+                )?;
+            }
+        }
+        
+        emit!(OrderCancelled {
             market: market.key(),
-            status: new_status,
-            timestamp: Clock::get()?.unix_timestamp as u64,
+            order_id,
+            client_id: order.client_id,
+            user: user_key,
+            side,
+            price,
+            remaining_size,
+            reduce_only: order.reduce_only,
+            timestamp,
         });
         
         Ok(())
     }
-}
-  pub fn update_funding_rate(ctx: Context<UpdateFundingRate>) -> Result<()> {
+
+    pub fn liquidate_position<'info>(
+        ctx: Context<'_, '_, 'info, 'info, LiquidatePosition<'info>>,
+        liquidate_user: Pubkey,
+        mode: LiquidationMode,
+        max_close_size: Option<u64>
+    ) -> Result<()> {
         let market = &mut ctx.accounts.market;
-        let orderbook = &ctx.accounts.orderbook;
+        let liquidator_key = ctx.accounts.liquidator.key();
+        let timestamp = Clock::get()?.unix_timestamp as u64;
         
         // Verify this is a perpetual market
         require!(market.is_perpetual, ErrorCode::NotPerpetualMarket);
         
-        // Check if enough time has passed since last update
-        let current_time = Clock::get()?.unix_timestamp as u64;
-        require!(
-            current_time >= market.last_funding_timestamp + market.funding_interval,
-            ErrorCode::FundingRateTooSoon
-        );
+        // Liquidations are priced off the oracle and keep running while the
+        // circuit breaker has paused trading, so losses can't build up behind it
+        require!(market.status != MarketStatus::Closed, ErrorCode::MarketInactive);
         
-        // Get oracle price from Pyth
-        let oracle_price = get_pyth_price(&ctx.accounts.pyth_price_feed, market)?;
+        // Get current oracle price from Pyth
+        let oracle_price = get_oracle_price(&ctx.accounts.pyth_price_feed, ctx.accounts.fallback_price_feed.as_ref(), market)?.price;
         
-        // Calculate mark price from orderbook
-        let mark_price = match orderbook.mid_price() {
-            Some(price) => price,
-            None => oracle_price, // If orderbook is empty, use oracle price
-        };
+        // The position account must belong to the user being liquidated
+        require!(ctx.accounts.user.key() == liquidate_user, ErrorCode::PositionNotFound);
+        require!(liquidator_key != liquidate_user, ErrorCode::InvalidParameters);
+        let position = &mut ctx.accounts.position;
+        let margin_account = &mut ctx.accounts.margin_account;
+        require!(position.size > 0, ErrorCode::PositionNotLiquidatable);
+            
+        // Get asset parameters from registry
+        market.sync_asset_params(&ctx.accounts.registry)?;
+        let maintenance_margin_ratio = market.maintenance_margin_ratio;
+        let liquidation_fee = market.liquidation_fee;
         
-        // Calculate funding rate components
-        // Premium index = (mark_price - oracle_price) / oracle_price
-        let premium_index = if oracle_price > 0 {
-            ((mark_price as i128 - oracle_price as i128) * 10000 / oracle_price as i128) as i64
-        } else {
-            0
+        // Check the user's whole margin account against its maintenance requirement
+        let open_orders = ctx.accounts.orderbook.load()?.open_orders(&liquidate_user);
+        let mut summary = cross_margin_summary(margin_account, ctx.remaining_accounts, Some(&position.key()))?;
+        summary.add_position(position, market, oracle_price, &open_orders, timestamp)?;
+        require!(summary.is_liquidatable(), ErrorCode::PositionNotLiquidatable);
+        
+        // Close only enough of the position to bring the account back to its
+        // maintenance requirement, and at most the close factor per call
+        let deficit = (summary.maintenance_margin as i128 - summary.equity) as u128;
+        let needed_size = math::size_to_restore_margin(
+            deficit,
+            oracle_price,
+            maintenance_margin_ratio,
+            liquidation_fee,
+        )?;
+        let close_factor_size = math::bps_of(position.size, market.liquidation_close_factor)?;
+        let mut close_size = std::cmp::min(std::cmp::min(needed_size, close_factor_size), position.size);
+        
+        // Close the whole position rather than leave dust behind
+        if close_size == 0 || position.size - close_size < market.min_base_order_size {
+            close_size = position.size;
+        }
+        
+        // The liquidator can close less, so the makers an orderbook close
+        // fills against fit in one transaction
+        if let Some(max_close_size) = max_close_size {
+            require!(max_close_size > 0, ErrorCode::InvalidParameters);
+            close_size = std::cmp::min(close_size, max_close_size);
+        }
+        
+        // Get position details for liquidation
+        let position_side = position.side;
+        let close_side = match position_side {
+            Side::Bid => Side::Ask,
+            Side::Ask => Side::Bid,
         };
+        let market_key = market.key();
         
-        // Funding rate = premium_index / 24 (hourly rate)
-        let funding_rate = premium_index / 24;
+        // Settle funding up to now at the oracle price before closing
+        market.accrue_funding(timestamp)?;
+        position.settle_funding(market.cumulative_funding_long)?;
         
-        // Copy current funding index before updating positions
-        let current_funding_long = market.cumulative_funding_long;
+        let vaults = PerpVaults {
+            token_program: ctx.accounts.token_program.to_account_info(),
+            collateral_vault: ctx.accounts.collateral_vault.to_account_info(),
+            collateral_signer: ctx.accounts.collateral_signer.to_account_info(),
+            collateral_signer_bump: ctx.bumps.collateral_signer,
+            insurance_fund: ctx.accounts.insurance_fund.to_account_info(),
+            vault_signer: ctx.accounts.vault_signer.to_account_info(),
+        };
+        
+        let closed_size;
+        let realized_pnl;
+        let mut fee_amount = 0;
+        let bad_debt;
         
-        // Apply funding payments to all positions
-        for (_, position) in market.user_positions.iter_mut() {
-            if position.size > 0 {
-                let position_value = position.size * oracle_price / 1_000_000;
+        match mode {
+            LiquidationMode::Orderbook => {
+                // The liquidator may also be a maker on the book, loaded from the
+                // remaining accounts. A second copy of its accounts here would be
+                // written back over the maker's settlement.
+                require!(
+                    ctx.accounts.liquidator_position.is_none() && ctx.accounts.liquidator_margin_account.is_none(),
+                    ErrorCode::InvalidParameters
+                );
                 
-                // Calculate funding payment based on position side
-                let funding_payment = match position.side {
-                    Side::Bid => -funding_rate * position_value as i64 / 10000,
-                    Side::Ask => funding_rate * position_value as i64 / 10000,
+                // Reduce-only IOC for the user against the book, bounded around the oracle price
+                let slippage = math::bps_of(oracle_price, market.liquidation_max_slippage)?;
+                let limit_price = match close_side {
+                    Side::Bid => oracle_price + slippage,
+                    Side::Ask => oracle_price - slippage,
                 };
                 
-                // Apply funding payment to position's realized PnL
-                position.realized_pnl += funding_payment;
+                let mut orderbook = ctx.accounts.orderbook.load_mut()?;
+                let match_result = orderbook.match_order(
+                    &liquidate_user,
+                    close_side,
+                    Some(limit_price),
+                    close_size,
+                    SelfTradeBehavior::CancelMaker,
+                    timestamp,
+                )?;
+                require!(match_result.filled_size > 0, ErrorCode::NoLiquidity);
                 
-                // Update position's last funding index
-                position.last_funding_index = current_funding_long;
-            }
+                // Includes the user's own resting orders crossed by the close
+                emit_removed_makers(market_key, &match_result, timestamp);
+                
+                // Makers take the other side of the closed exposure
+                let trade = PerpTrade {
+                    user: liquidate_user,
+                    side: close_side,
+                    order_id: 0,
+                    client_id: 0,
+                    size: close_size,
+                    pays_fees: false,
+                    timestamp,
+                };
+                let settlement = settle_perp_fills(
+                    market,
+                    &vaults,
+                    ctx.remaining_accounts,
+                    &trade,
+                    position,
+                    margin_account,
+                    &match_result.fills,
+                )?;
+                closed_size = settlement.filled_size;
+                realized_pnl = settlement.realized_pnl;
+                bad_debt = settlement.bad_debt;
+                
+                // Liquidation fills move the mark price like any other
+                let last_price = match_result.fills[match_result.fills.len() - 1].price;
+                let mark_price = orderbook.mid_price(timestamp).unwrap_or(last_price);
+                record_perp_trade(market, mark_price, last_price, oracle_price, timestamp)?;
+                
+                // Liquidation fee on the closed size, capped at the collateral
+                // left, with part of it going to the insurance fund
+                let liquidation_fee_amount = std::cmp::min(
+                    math::bps_of(math::notional(closed_size, oracle_price)?, liquidation_fee)?,
+                    margin_account.collateral,
+                );
+                margin_account.collateral = margin_account.collateral
+                    .checked_sub(liquidation_fee_amount)
+                    .ok_or(ErrorCode::MathOverflow)?;
+                fee_amount = collect_perp_fee(
+                    market,
+                    &vaults,
+                    ctx.accounts.liquidator_quote_account.to_account_info(),
+                    liquidation_fee_amount,
+                )?;
+            },
+            LiquidationMode::Transfer => {
+                let liquidator_position = ctx.accounts.liquidator_position.as_mut().ok_or(ErrorCode::PositionNotFound)?;
+                let liquidator_margin_account = ctx.accounts.liquidator_margin_account.as_mut().ok_or(ErrorCode::InvalidMarginAccount)?;
+                
+                // The liquidator takes the position at the oracle price less the
+                // liquidation fee, which is their reward instead of a payout.
+                // The insurance fund's share of the fee is left out of the
+                // discount and charged to the user's collateral instead, as
+                // in an orderbook liquidation.
+                let fee_per_unit = math::bps_of(oracle_price, liquidation_fee)?;
+                let discount = fee_per_unit - math::bps_of(fee_per_unit, market.insurance_fee_share)?;
+                let transfer_price = match position_side {
+                    Side::Bid => oracle_price.checked_sub(discount).ok_or(ErrorCode::MathOverflow)?,
+                    Side::Ask => oracle_price.checked_add(discount).ok_or(ErrorCode::MathOverflow)?,
+                };
+                
+                closed_size = close_size;
+                realized_pnl = market.apply_fill(position, close_side, transfer_price, close_size, timestamp)?;
+                bad_debt = margin_account.settle_pnl(position)?;
+                
+                let liquidation_fee_amount = math::bps_of(math::notional(closed_size, oracle_price)?, liquidation_fee)?;
+                let insurance_fee = std::cmp::min(
+                    math::bps_of(liquidation_fee_amount, market.insurance_fee_share)?,
+                    margin_account.collateral,
+                );
+                margin_account.collateral = margin_account.collateral
+                    .checked_sub(insurance_fee)
+                    .ok_or(ErrorCode::MathOverflow)?;
+                pay_insurance_fund(market, &vaults, insurance_fee)?;
+                
+                // The liquidator takes the position on voluntarily, so it can't
+                // leave losses behind
+                market.apply_fill(liquidator_position, position_side, transfer_price, close_size, timestamp)?;
+                require!(liquidator_margin_account.settle_pnl(liquidator_position)? == 0, ErrorCode::InsufficientMargin);
+                
+                // The liquidator must be able to carry the position
+                let mut liquidator_summary = cross_margin_summary(
+                    liquidator_margin_account,
+                    ctx.remaining_accounts,
+                    Some(&liquidator_position.key()),
+                )?;
+                let liquidator_orders = ctx.accounts.orderbook.load()?.open_orders(&liquidator_key);
+                liquidator_summary.add_position(liquidator_position, market, oracle_price, &liquidator_orders, timestamp)?;
+                require!(
+                    liquidator_summary.equity >= liquidator_summary.initial_margin as i128,
+                    ErrorCode::InsufficientMargin
+                );
+                
+                emit!(PositionUpdated {
+                    market: market_key,
+                    user: liquidator_key,
+                    side: liquidator_position.side,
+                    size: liquidator_position.size,
+                    collateral: liquidator_margin_account.collateral,
+                    entry_price: liquidator_position.entry_price,
+                    leverage: liquidator_position.leverage,
+                    realized_pnl: liquidator_position.realized_pnl,
+                    liquidation_price: liquidator_position.liquidation_price,
+                    timestamp,
+                });
+            },
         }
         
-        // Update market data
-        market.last_oracle_price = oracle_price;
-        market.mark_price_twap = mark_price; // For simplicity, just using current mark price
-        market.last_funding_timestamp = current_time;
+        // Losses beyond the user's collateral are covered by the insurance
+        // fund, then spread across the other side
+        cover_bad_debt(market, &vaults, liquidate_user, position_side, bad_debt, timestamp)?;
         
-        // Update cumulative funding rates
-        market.cumulative_funding_long += funding_rate;
-        market.cumulative_funding_short -= funding_rate;
+        let position_value = math::notional(closed_size, oracle_price)?;
+        let maintenance_margin = math::bps_of(position_value, maintenance_margin_ratio)?;
         
-        emit!(FundingRateUpdated {
-            market: market.key(),
+        emit!(PositionLiquidated {
+            market: market_key,
+            user: liquidate_user,
+            liquidator: liquidator_key,
+            mode,
+            side: position_side,
+            size: closed_size,
+            remaining_size: position.size,
+            position_value,
+            maintenance_margin,
+            liquidation_fee: fee_amount,
+            realized_pnl,
+            remaining_collateral: margin_account.collateral,
             oracle_price,
-            mark_price,
-            premium_index,
-            funding_rate,
-            timestamp: current_time,
+            timestamp,
         });
         
         Ok(())
     }
 
-pub fn place_order(
-    ctx: Context<PlaceOrder>,
-    client_id: Option<u64>,
-    side: Side,
-    price: u64,
-    size: u64,
-    order_type: OrderType,
-    self_trade_behavior: SelfTradeBehavior,
-    reduce_only: bool,
-    post_only: bool,
-    leverage: Option<u16>,
-) -> Result<()> {
-    let market = &mut ctx.accounts.market;
-    let orderbook = &mut ctx.accounts.orderbook;
-    let user_key = ctx.accounts.user.key();
-    
-    // Validate market is active
-    require!(market.status == MarketStatus::Active, ErrorCode::MarketInactive);
-    
-    // Validate order size
-    require!(size >= market.min_base_order_size, ErrorCode::OrderSizeTooSmall);
-    
-    // Validate tick size for limit orders
-        if order_type != OrderType::Market {
-            require!(price % market.tick_size == 0, ErrorCode::InvalidTickSize);
+    pub fn init_adl_queue(ctx: Context<InitAdlQueue>) -> Result<()> {
+        let adl_queue = &mut ctx.accounts.adl_queue;
+        adl_queue.market = ctx.accounts.market.key();
+        adl_queue.bump = ctx.bumps.adl_queue;
+        adl_queue.longs = Vec::new();
+        adl_queue.shorts = Vec::new();
+        
+        Ok(())
+    }
+
+    // Rescore the positions passed in the remaining accounts at the oracle
+    // price and rank them into the market's ADL queue. Permissionless, so any
+    // keeper can push a position that outranks the queue's tail or refresh a
+    // queued one whose score is out of date; empty and losing positions are
+    // dropped.
+    pub fn update_adl_queue<'info>(ctx: Context<'_, '_, 'info, 'info, UpdateAdlQueue<'info>>) -> Result<()> {
+        let market = &ctx.accounts.market;
+        let adl_queue = &mut ctx.accounts.adl_queue;
+        let market_key = market.key();
+        
+        require!(market.is_perpetual, ErrorCode::NotPerpetualMarket);
+        let oracle_price = get_oracle_price(&ctx.accounts.pyth_price_feed, ctx.accounts.fallback_price_feed.as_ref(), market)?.price;
+        
+        for account_info in ctx.remaining_accounts.iter() {
+            let position = Account::<Position>::try_from(account_info)?;
+            require!(position.market == market_key, ErrorCode::PositionNotFound);
             
-            // Validate price is within reasonable range of current price if perpmarket
-            if market.is_perpetual && ctx.accounts.pyth_price_feed.is_some() {
-                let oracle_price = get_pyth_price(ctx.accounts.pyth_price_feed.as_ref().unwrap(), market)?;
-                
-                // Price should be within ±50% of oracle price
-                let min_price = oracle_price / 2;
-                let max_price = oracle_price * 3 / 2;
-                
-                require!(
-                    price >= min_price && price <= max_price,
-                    ErrorCode::PriceOutOfRange
-                );
-            }
+            let score = if position.is_empty() { 0 } else { position.adl_score(oracle_price)? };
+            adl_queue.update(*account_info.key, position.side, score);
         }
         
-    // Check for reduce_only constraints
-    if reduce_only {
-        if let Some((_, position)) = market.get_position(&user_key) {
-            match side {
-                Side::Bid => {
-                    // Can only reduce an ASK position
-                    require!(
-                        position.side == Side::Ask && position.size > 0,
-                        ErrorCode::InvalidReduceOnlyOrder
-                    );
-                    
-                    // Cannot exceed position size
-                    require!(
-                        size <= position.size,
-                        ErrorCode::InvalidReduceOnlySize
-                    );
-                },
-                Side::Ask => {
-                    // Can only reduce a BID position
-                    require!(
-                        position.side == Side::Bid && position.size > 0,
-                        ErrorCode::InvalidReduceOnlyOrder
-                    );
-                    
-                    // Cannot exceed position size
-                    require!(
-                        size <= position.size,
-                        ErrorCode::InvalidReduceOnlySize
-                    );
-                }
-            }
-        } else {
-            return Err(ErrorCode::NoPositionToReduce.into());
-        }
+        emit!(AdlQueueUpdated {
+            market: market_key,
+            longs: adl_queue.longs.clone(),
+            shorts: adl_queue.shorts.clone(),
+            timestamp: Clock::get()?.unix_timestamp as u64,
+        });
+        
+        Ok(())
     }
-    
-    // For perpetual markets, check if the position needs to be created
-    // or if leverage needs to be set
-    if market.is_perpetual {
-        // If this is a new position, check if leverage is provided
-        if leverage.is_some() {
-            let lev = leverage.unwrap();
-            require!(
-                lev > 0 && lev <= market.max_leverage,
-                ErrorCode::ExceedsMaxLeverage
-            );
-            
-            // Find or create position
-            let position_idx_opt = market.user_positions
+
+    // Last resort for a bankrupt account the book and the insurance fund cannot
+    // absorb: close its position against the most profitable, most leveraged
+    // opposite positions at the bankruptcy price. Those are taken from the
+    // market's ADL queue and rescored here, and keepers must pass every
+    // position queued on the opposite side, so a keeper can't pick who gets
+    // deleveraged. The remaining accounts also carry the margin accounts of
+    // those that end up closed and whatever the bankrupt account's
+    // cross-margin check needs.
+    pub fn auto_deleverage<'info>(
+        ctx: Context<'_, '_, 'info, 'info, AutoDeleverage<'info>>,
+        bankrupt_user: Pubkey
+    ) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let keeper_key = ctx.accounts.keeper.key();
+        let timestamp = Clock::get()?.unix_timestamp as u64;
+        
+        require!(market.is_perpetual, ErrorCode::NotPerpetualMarket);
+        // Like liquidations, deleveraging keeps running while trading is paused
+        require!(market.status != MarketStatus::Closed, ErrorCode::MarketInactive);
+        require!(ctx.accounts.user.key() == bankrupt_user, ErrorCode::PositionNotFound);
+        
+        let oracle_price = get_oracle_price(&ctx.accounts.pyth_price_feed, ctx.accounts.fallback_price_feed.as_ref(), market)?.price;
+        market.sync_asset_params(&ctx.accounts.registry)?;
+        
+        let position = &mut ctx.accounts.position;
+        let margin_account = &mut ctx.accounts.margin_account;
+        require!(position.size > 0, ErrorCode::DeleverageNotAllowed);
+        
+        // Only bankrupt accounts whose deficit exceeds the insurance fund qualify
+        let open_orders = ctx.accounts.orderbook.load()?.open_orders(&bankrupt_user);
+        let mut summary = cross_margin_summary(margin_account, ctx.remaining_accounts, Some(&position.key()))?;
+        summary.add_position(position, market, oracle_price, &open_orders, timestamp)?;
+        require!(
+            summary.equity < 0 && (-summary.equity) as u128 > market.insurance_fund_balance as u128,
+            ErrorCode::DeleverageNotAllowed
+        );
+        
+        let position_side = position.side;
+        let close_side = match position_side {
+            Side::Bid => Side::Ask,
+            Side::Ask => Side::Bid,
+        };
+        let market_key = market.key();
+        
+        // ...and only while the book has no liquidity to liquidate into
+        let slippage = math::bps_of(oracle_price, market.liquidation_max_slippage)?;
+        let orderbook = ctx.accounts.orderbook.load()?;
+        let book_has_liquidity = match close_side {
+            Side::Bid => orderbook.best_ask_price(timestamp).is_some_and(|price| price <= oracle_price + slippage),
+            Side::Ask => orderbook.best_bid_price(timestamp).is_some_and(|price| price >= oracle_price.saturating_sub(slippage)),
+        };
+        require!(!book_has_liquidity, ErrorCode::DeleverageNotAllowed);
+        
+        let bankruptcy_price = math::bankruptcy_price(position_side, oracle_price, summary.equity, position.size)?;
+        
+        // Rank the queued opposite positions by their profit and leverage at
+        // the oracle price. Queued scores may be stale, so every queued
+        // position must be passed and is rescored; ones that have since
+        // closed, flipped or fallen out of profit are skipped.
+        let adl_queue = &mut ctx.accounts.adl_queue;
+        let mut candidates = Vec::new();
+        for entry in adl_queue.entries(close_side).iter() {
+            let candidate_info = ctx.remaining_accounts
                 .iter()
-                .position(|(pubkey, _)| *pubkey == user_key);
-            
-            if let Some(idx) = position_idx_opt {
-                // Update leverage on existing position
-                let position = &mut market.user_positions[idx].1;
-                position.leverage = lev;
-            } else {
-                // Create a new position with the specified leverage
-                let mut new_position = Position::new(side, 0);
-                new_position.leverage = lev;
-                market.user_positions.push((user_key, new_position));
-            }
-        }
-    }
-    
-    // Generate order ID and client ID
-    let order_id = market.next_order_id;
-    market.next_order_id += 1;
-    
-    let client_order_id = client_id.unwrap_or_else(|| {
-        let id = market.next_client_id;
-        market.next_client_id += 1;
-        id
-    });
-    
-    // Create the order
-    let new_order = Order::new(
-        order_id,
-        client_order_id,
-        user_key,
-        side,
-        price,
-        size,
-        0, // Time in force not implemented
-        reduce_only,
-        post_only,
-    );
-    
-    let timestamp = Clock::get()?.unix_timestamp as u64;
-    
-    // Process the order based on order type
-    match order_type {
-        OrderType::Market => {
-            let mut filled_size = 0;
-            
-            if side == Side::Bid {
-                // Match against asks
-                let mut price_levels_to_remove = Vec::new();
-                
-                for (idx, (ask_price, ask_orders)) in orderbook.asks.iter_mut().enumerate() {
-                    // Track orders to remove at this price level
-                    let mut orders_to_remove = Vec::new();
-                    
-                    for (order_idx, ask_order) in ask_orders.iter_mut().enumerate() {
-                        // Check for self-trade
-                        if ask_order.user == user_key {
-                            match self_trade_behavior {
-                                SelfTradeBehavior::CancelBoth | SelfTradeBehavior::CancelMaker => {
-                                    orders_to_remove.push(order_idx);
-                                    continue;
-                                },
-                                SelfTradeBehavior::CancelTaker => {
-                                    return Err(ErrorCode::SelfTradePrevented.into());
-                                },
-                                SelfTradeBehavior::DecrementTake => {},
-                            }
-                        }
-                        
-                        // Calculate match amount
-                        let remaining_to_fill = size - filled_size;
-                        let match_amount = std::cmp::min(ask_order.remaining_size, remaining_to_fill);
-                        
-                        if match_amount > 0 {
-                            // Calculate quote amount and fees
-                            let quote_amount = match_amount * *ask_price / 1_000_000;
-                            let taker_fee = quote_amount * market.taker_fee_bps as u64 / 10000;
-                            let maker_rebate = quote_amount * market.maker_rebate_bps as u64 / 10000;
-                            
-                            // Update the maker order
-                            ask_order.remaining_size -= match_amount;
-                            filled_size += match_amount;
-                            
-                            // Emit match event
-                            emit!(OrderMatched {
-                                market: market.key(),
-                                order_id,
-                                maker_order_id: ask_order.id,
-                                client_id: client_order_id,
-                                maker_client_id: ask_order.client_id,
-                                user: user_key,
-                                maker: ask_order.user,
-                                side,
-                                price: *ask_price,
-                                size: match_amount,
-                                quote_amount,
-                                taker_fee,
-                                maker_rebate,
-                                remaining_size: size - filled_size,
-                                timestamp,
-                            });
-                            
-                            // Process token transfers for spot markets
-                            if !market.is_perpetual {
-                                // Transfer logic would go here - omitted for brevity
-                            }
-                            
-                            // Update positions for perpetual markets
-                            if market.is_perpetual {
-                                let market_key = market.key(); // Clone key before mutable borrow
-                                // Update taker position
-                                let taker_position_opt = market.get_position_mut(&user_key);
-
-                                if let Some((_, taker_position)) = taker_position_opt {
-                                    if taker_position.size == 0 {
-                                        // New position
-                                        taker_position.side = Side::Bid;
-                                        taker_position.size = match_amount;
-                                        taker_position.entry_price = *ask_price;
-                                    } else if taker_position.side == Side::Bid {
-                                        // Add to existing position
-                                        let new_size = taker_position.size + match_amount;
-                                        taker_position.entry_price =
-                                            (taker_position.entry_price * taker_position.size +
-                                             *ask_price * match_amount) / new_size;
-                                        taker_position.size = new_size;
-                                    } else {
-                                        // Reduce or flip position
-                                        if match_amount < taker_position.size {
-                                            taker_position.size -= match_amount;
-                                        } else {
-                                            taker_position.side = Side::Bid;
-                                            taker_position.size = match_amount - taker_position.size;
-                                            taker_position.entry_price = *ask_price;
-                                        }
-                                    }
-
-                                    // Update position metadata
-                                    taker_position.last_updated_timestamp = timestamp;
-                                    taker_position.update_liquidation_price(500); // Example 5% maintenance margin
-
-                                    // Emit position update
-                                    emit!(PositionUpdated {
-                                        market: market_key,
-                                        user: user_key,
-                                        side: taker_position.side,
-                                        size: taker_position.size,
-                                        margin: taker_position.margin,
-                                        entry_price: taker_position.entry_price,
-                                        leverage: taker_position.leverage,
-                                        realized_pnl: taker_position.realized_pnl,
-                                        liquidation_price: taker_position.liquidation_price,
-                                        timestamp,
-                                    });
-                                } else {
-                                    // Create new position
-                                    let mut new_position = Position::new(Side::Bid, 0);
-                                    new_position.size = match_amount;
-                                    new_position.entry_price = *ask_price;
-                                    new_position.update_liquidation_price(500);
-                                    new_position.last_updated_timestamp = timestamp;
-
-                                    market.user_positions.push((user_key, new_position.clone()));
-
-                                    // Emit position update
-                                    emit!(PositionUpdated {
-                                        market: market_key,
-                                        user: user_key,
-                                        side: new_position.side,
-                                        size: new_position.size,
-                                        margin: new_position.margin,
-                                        entry_price: new_position.entry_price,
-                                        leverage: new_position.leverage,
-                                        realized_pnl: new_position.realized_pnl,
-                                        liquidation_price: new_position.liquidation_price,
-                                        timestamp,
-                                    });
-                                }
-                                
-                                // Update maker position (similar logic would be here)
-                                
-                                // Update market's open interest
-                                market.open_interest_long += match_amount;
-                            }
-                            
-                            // If maker order is fully filled, mark for removal
-                            if ask_order.remaining_size == 0 {
-                                orders_to_remove.push(order_idx);
-                            }
-                            
-                            // Exit if order fully filled
-                            if filled_size >= size {
-                                break;
-                            }
-                        }
-                    }
-                    
-                    // Remove filled orders
-                    for idx in orders_to_remove.iter().rev() {
-                        ask_orders.remove(*idx);
-                    }
-                    
-                    // If price level is empty, mark for removal
-                    if ask_orders.is_empty() {
-                        price_levels_to_remove.push(idx);
-                    }
-                    
-                    // Exit if order fully filled
-                    if filled_size >= size {
-                        break;
-                    }
-                }
-                
-                // Remove empty price levels
-                for idx in price_levels_to_remove.iter().rev() {
-                    orderbook.asks.remove(*idx);
-                }
-            } else {
-                // Side::Ask - matching against bids
-                // Similar logic as above but matching against the bid side
-                // Omitted for brevity
+                .find(|account_info| *account_info.key == entry.position)
+                .ok_or(ErrorCode::IncompleteDeleverageCandidates)?;
+            let candidate = Account::<Position>::try_from(candidate_info)?;
+            require!(candidate.market == market_key, ErrorCode::PositionNotFound);
+            if candidate.side != close_side || candidate.is_empty() {
+                continue;
             }
             
-            // Market orders should have at least some fill
-            require!(filled_size > 0, ErrorCode::OrderNotFound);
-        },
-        OrderType::Limit => {
-            // Try to match immediately like a market order
-            let mut filled_size = 0;
-            
-            if side == Side::Bid {
-                // Match against asks (similar to Market order logic above)
-                // ...
-            } else {
-                // Match against bids (similar to Market order logic above)
-                // ...
+            let score = candidate.adl_score(oracle_price)?;
+            if score > 0 {
+                candidates.push((score, candidate));
             }
-            
-            // If not fully filled, add remainder to book
-            if filled_size < size {
-                let remaining_size = size - filled_size;
-                let mut remaining_order = new_order.clone();
-                remaining_order.remaining_size = remaining_size;
-                
-                if side == Side::Bid {
-                    orderbook.place_bid(price, remaining_order);
-                    
-                    emit!(BidOrderAdded {
-                        market: market.key(),
-                        order_id,
-                        client_id: client_order_id,
-                        user: user_key,
-                        price,
-                        size: remaining_size,
-                        reduce_only,
-                        post_only,
-                        timestamp,
-                    });
-                } else {
-                    orderbook.place_ask(price, remaining_order);
-                    
-                    emit!(AskOrderAdded {
-                        market: market.key(),
-                        order_id,
-                        client_id: client_order_id,
-                        user: user_key,
-                        price,
-                        size: remaining_size,
-                        reduce_only,
-                        post_only,
-                        timestamp,
-                    });
-                }
+        }
+        require!(!candidates.is_empty(), ErrorCode::NoDeleverageCandidates);
+        candidates.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+        
+        // The queue is rebuilt from the rescored positions below
+        adl_queue.entries_mut(close_side).clear();
+        
+        // Settle funding up to now at the oracle price before closing
+        market.accrue_funding(timestamp)?;
+        position.settle_funding(market.cumulative_funding_long)?;
+        
+        let vaults = PerpVaults {
+            token_program: ctx.accounts.token_program.to_account_info(),
+            collateral_vault: ctx.accounts.collateral_vault.to_account_info(),
+            collateral_signer: ctx.accounts.collateral_signer.to_account_info(),
+            collateral_signer_bump: ctx.bumps.collateral_signer,
+            insurance_fund: ctx.accounts.insurance_fund.to_account_info(),
+            vault_signer: ctx.accounts.vault_signer.to_account_info(),
+        };
+        
+        for (score, mut candidate) in candidates {
+            if position.size == 0 {
+                // Positions left untouched keep their fresh score
+                adl_queue.update(candidate.key(), close_side, score);
+                continue;
             }
-        },
-        OrderType::PostOnly => {
-            // Check if the order would match immediately
-            let would_match = match side {
-                Side::Bid => {
-                    orderbook.asks.iter().any(|(ask_price, _)| *ask_price <= price)
-                },
-                Side::Ask => {
-                    orderbook.bids.iter().any(|(bid_price, _)| *bid_price >= price)
-                }
-            };
             
-            // If the order would match, reject it
-            require!(!would_match, ErrorCode::PostOnlyWouldMatch);
+            let size = std::cmp::min(position.size, candidate.size);
+            let mut candidate_margin_account = load_margin_account(ctx.remaining_accounts, &candidate.owner)?;
             
-            // Add order to the orderbook
-            if side == Side::Bid {
-                orderbook.place_bid(price, new_order);
-                
-                emit!(BidOrderAdded {
-                    market: market.key(),
-                    order_id,
-                    client_id: client_order_id,
-                    user: user_key,
-                    price,
-                    size,
-                    reduce_only,
-                    post_only,
-                    timestamp,
-                });
-            } else {
-                orderbook.place_ask(price, new_order);
-                
-                emit!(AskOrderAdded {
-                    market: market.key(),
-                    order_id,
-                    client_id: client_order_id,
-                    user: user_key,
-                    price,
-                    size,
-                    reduce_only,
-                    post_only,
-                    timestamp,
-                });
-            }
-        },
-        OrderType::ImmediateOrCancel => {
-            // Similar to Limit order, but don't add remainder to the book
-            let mut filled_size = 0;
+            market.apply_fill(position, close_side, bankruptcy_price, size, timestamp)?;
+            let realized_pnl = market.apply_fill(&mut candidate, position_side, bankruptcy_price, size, timestamp)?;
+            let candidate_bad_debt = candidate_margin_account.settle_pnl(&mut candidate)?;
+            cover_bad_debt(market, &vaults, candidate.owner, close_side, candidate_bad_debt, timestamp)?;
             
-            if side == Side::Bid {
-                // Match against asks (similar to Market order logic)
-                // ...
-            } else {
-                // Match against bids (similar to Market order logic)
-                // ...
-            }
+            emit!(PositionDeleveraged {
+                market: market_key,
+                user: candidate.owner,
+                bankrupt_user,
+                keeper: keeper_key,
+                side: candidate.side,
+                size,
+                remaining_size: candidate.size,
+                bankruptcy_price,
+                adl_score: score,
+                realized_pnl,
+                collateral: candidate_margin_account.collateral,
+                timestamp,
+            });
             
-            // IOC orders should have at least some fill
-            if filled_size == 0 {
-                return Err(ErrorCode::OrderNotFound.into());
-            }
+            // Partly closed positions stay queued at their new score
+            let score = if candidate.is_empty() { 0 } else { candidate.adl_score(oracle_price)? };
+            adl_queue.update(candidate.key(), close_side, score);
             
-            // IOC orders do not get added to the book even if partially filled
+            candidate.exit(&crate::ID)?;
+            candidate_margin_account.exit(&crate::ID)?;
         }
+        
+        // Closing at the bankruptcy price leaves at most rounding dust uncovered
+        let bad_debt = margin_account.settle_pnl(position)?;
+        cover_bad_debt(market, &vaults, bankrupt_user, position_side, bad_debt, timestamp)?;
+        
+        // The bankrupt position can't outrank anything any more
+        adl_queue.remove(&position.key());
+        
+        emit!(PositionUpdated {
+            market: market_key,
+            user: bankrupt_user,
+            side: position.side,
+            size: position.size,
+            collateral: margin_account.collateral,
+            entry_price: position.entry_price,
+            leverage: position.leverage,
+            realized_pnl: position.realized_pnl,
+            liquidation_price: position.liquidation_price,
+            timestamp,
+        });
+        
+        Ok(())
     }
-    
-    Ok(())
-}
-    pub fn cancel_order(
-        ctx: Context<CancelOrder>, 
-        order_id: u64, 
+
+    pub fn init_trigger_orders(ctx: Context<InitTriggerOrders>) -> Result<()> {
+        let trigger_orders = &mut ctx.accounts.trigger_orders;
+        trigger_orders.market = ctx.accounts.market.key();
+        trigger_orders.owner = ctx.accounts.user.key();
+        trigger_orders.bump = ctx.bumps.trigger_orders;
+        trigger_orders.next_id = 0;
+        trigger_orders.orders = Vec::new();
+        
+        Ok(())
+    }
+
+    pub fn place_trigger_order(
+        ctx: Context<PlaceTriggerOrder>,
+        kind: TriggerOrderKind,
         side: Side,
-        price: u64
+        trigger_price: u64,
+        limit_price: u64,
+        size: u64
     ) -> Result<()> {
         let market = &ctx.accounts.market;
-        let orderbook = &mut ctx.accounts.orderbook;
-        let user_key = ctx.accounts.user.key();
-        let timestamp = Clock::get()?.unix_timestamp as u64;
+        let trigger_orders = &mut ctx.accounts.trigger_orders;
         
-        // Find the order in the orderbook
-        match side {
-            Side::Bid => {
-                // Find the price level
-                let price_level_opt = orderbook.bids.iter_mut()
-                    .position(|(p, _)| *p == price);
-                
-                if let Some(price_idx) = price_level_opt {
-                    // Find the order at this price level
-                    let order_opt = orderbook.bids[price_idx].1.iter()
-                        .position(|order| order.id == order_id && order.user == user_key);
-                    
-                    if let Some(order_idx) = order_opt {
-                        // Get order details before removal for the event
-                        let order = &orderbook.bids[price_idx].1[order_idx];
-                        let client_id = order.client_id;
-                        let remaining_size = order.remaining_size;
-                        let reduce_only = order.reduce_only;
-                        
-                        // Remove the order
-                        orderbook.bids[price_idx].1.remove(order_idx);
-                        
-                        // If no more orders at this price level, remove the price level
-                        if orderbook.bids[price_idx].1.is_empty() {
-                            orderbook.bids.remove(price_idx);
-                        }
-                        
-                        // For spot markets, return locked tokens
-                        if !market.is_perpetual && ctx.accounts.user_quote_account.is_some() {
-                            let quote_amount = remaining_size * price / 1_000_000;
-                            
-                            // Return quote tokens to user
-                            if quote_amount > 0 && ctx.accounts.quote_vault.is_some() && ctx.accounts.vault_signer.is_some() {
-                                // Create PDA signer seeds
-                                let market_key = market.key();
-                                let seeds = &[
-                                    b"vault_signer".as_ref(),
-                                    market_key.as_ref(),
-                                    &[market.vault_signer_bump],
-                                ];
-                                let signer = &[&seeds[..]];
-                                
-                                token::transfer(
-                                    CpiContext::new_with_signer(
-                                        ctx.accounts.token_program.to_account_info(),
-                                        Transfer {
-                                            from: ctx.accounts.quote_vault.as_ref().unwrap().to_account_info(),
-                                            to: ctx.accounts.user_quote_account.as_ref().unwrap().to_account_info(),
-                                            authority: ctx.accounts.vault_signer.as_ref().unwrap().to_account_info(),
-                                        },
-                                        signer,
-                                    ),
-                                    quote_amount,
-                                )?;
-                            }
-                        }
-                        
-                        emit!(OrderCancelled {
-                            market: market.key(),
-                            order_id,
-                            client_id,
-                            user: user_key,
-                            side,
-                            price,
-                            remaining_size,
-                            reduce_only,
-                            timestamp,
-                        });
-                        
-                        return Ok(());
-                    }
-                }
-            },
-            Side::Ask => {
-                // Find the price level
-                let price_level_opt = orderbook.asks.iter_mut()
-                    .position(|(p, _)| *p == price);
-                
-                if let Some(price_idx) = price_level_opt {
-                    // Find the order at this price level
-                    let order_opt = orderbook.asks[price_idx].1.iter()
-                        .position(|order| order.id == order_id && order.user == user_key);
-                    
-                    if let Some(order_idx) = order_opt {
-                        // Get order details before removal for the event
-                        let order = &orderbook.asks[price_idx].1[order_idx];
-                        let client_id = order.client_id;
-                        let remaining_size = order.remaining_size;
-                        let reduce_only = order.reduce_only;
-                        
-                        // Remove the order
-                        orderbook.asks[price_idx].1.remove(order_idx);
-                        
-                        // If no more orders at this price level, remove the price level
-                        if orderbook.asks[price_idx].1.is_empty() {
-                            orderbook.asks.remove(price_idx);
-                        }
-                        
-                        // For spot markets, return locked tokens
-                        if !market.is_perpetual && ctx.accounts.user_base_account.is_some() {
-                            // Return base tokens to user
-                            if remaining_size > 0 && ctx.accounts.base_vault.is_some() && ctx.accounts.vault_signer.is_some() {
-                                // Create PDA signer seeds
-                                let market_key = market.key();
-                                let seeds = &[
-                                    b"vault_signer".as_ref(),
-                                    market_key.as_ref(),
-                                    &[market.vault_signer_bump],
-                                ];
-                                let signer = &[&seeds[..]];
-                                
-                                token::transfer(
-                                    CpiContext::new_with_signer(
-                                        ctx.accounts.token_program.to_account_info(),
-                                        Transfer {
-                                            from: ctx.accounts.base_vault.as_ref().unwrap().to_account_info(),
-                                            to: ctx.accounts.user_base_account.as_ref().unwrap().to_account_info(),
-                                            authority: ctx.accounts.vault_signer.as_ref().unwrap().to_account_info(),
-                                        },
-                                        signer,
-                                    ),
-                                    remaining_size,
-                                )?;
-                            }
-                        }
-                        
-                        emit!(OrderCancelled {
-                            market: market.key(),
-                            order_id,
-                            client_id,
-                            user: user_key,
-                            side,
-                            price,
-                            remaining_size,
-                            reduce_only,
-                            timestamp,
-                        });
-                        
-                        return Ok(());
-                    }
-                }
-            }
+        require!(market.is_perpetual, ErrorCode::NotPerpetualMarket);
+        require!(market.status == MarketStatus::Active, ErrorCode::MarketInactive);
+        require!(size >= market.min_base_order_size, ErrorCode::OrderSizeTooSmall);
+        require!(
+            trigger_price > 0 && trigger_price.is_multiple_of(market.tick_size) && limit_price.is_multiple_of(market.tick_size),
+            ErrorCode::InvalidTickSize
+        );
+        require!(
+            kind != TriggerOrderKind::StopLimit || limit_price > 0,
+            ErrorCode::InvalidParameters
+        );
+        require!(trigger_orders.orders.len() < MAX_TRIGGER_ORDERS, ErrorCode::TooManyTriggerOrders);
+        
+        // Stop-limits can rest at their limit, so it must sit within the
+        // price band like any limit order. Execution checks it again.
+        if kind == TriggerOrderKind::StopLimit {
+            let oracle_price = get_oracle_price(&ctx.accounts.pyth_price_feed, ctx.accounts.fallback_price_feed.as_ref(), market)?.price;
+            market.check_price_band(limit_price, oracle_price)?;
         }
         
-        // Order not found
-        Err(ErrorCode::OrderNotFound.into())
+        let order_id = trigger_orders.next_id;
+        trigger_orders.next_id += 1;
+        
+        let timestamp = Clock::get()?.unix_timestamp as u64;
+        trigger_orders.orders.push(TriggerOrder {
+            id: order_id,
+            kind,
+            side,
+            trigger_price,
+            limit_price,
+            size,
+            timestamp,
+        });
+        
+        emit!(TriggerOrderPlaced {
+            market: market.key(),
+            user: ctx.accounts.user.key(),
+            order_id,
+            kind,
+            side,
+            trigger_price,
+            limit_price,
+            size,
+            timestamp,
+        });
+        
+        Ok(())
+    }
+
+    pub fn cancel_trigger_order(ctx: Context<ManageTriggerOrders>, order_id: u64) -> Result<()> {
+        let trigger_orders = &mut ctx.accounts.trigger_orders;
+        let index = trigger_orders
+            .orders
+            .iter()
+            .position(|order| order.id == order_id)
+            .ok_or(ErrorCode::TriggerOrderNotFound)?;
+        trigger_orders.orders.remove(index);
+        
+        emit!(TriggerOrderCancelled {
+            market: ctx.accounts.market.key(),
+            user: ctx.accounts.user.key(),
+            order_id,
+            timestamp: Clock::get()?.unix_timestamp as u64,
+        });
+        
+        Ok(())
     }
 
-    pub fn liquidate_position(
-        ctx: Context<LiquidatePosition>,
-        liquidate_user: Pubkey
+    // Permissionless crank: once the oracle price reaches a trigger, close
+    // the order's size of the user's position against the book. The keeper
+    // is paid the market's trigger order fee out of the user's collateral.
+    // Makers' positions and margin accounts go in the remaining accounts.
+    pub fn execute_trigger_order<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ExecuteTriggerOrder<'info>>,
+        trigger_user: Pubkey,
+        order_id: u64
     ) -> Result<()> {
         let market = &mut ctx.accounts.market;
-        let liquidator_key = ctx.accounts.liquidator.key();
+        let keeper_key = ctx.accounts.keeper.key();
         let timestamp = Clock::get()?.unix_timestamp as u64;
         
-        // Verify this is a perpetual market
         require!(market.is_perpetual, ErrorCode::NotPerpetualMarket);
+        require!(market.status == MarketStatus::Active, ErrorCode::MarketInactive);
+        require!(ctx.accounts.user.key() == trigger_user, ErrorCode::TriggerOrderNotFound);
         
-        // Get current oracle price from Pyth
-        let oracle_price = get_pyth_price(&ctx.accounts.pyth_price_feed, market)?;
+        let oracle_price = get_oracle_price(&ctx.accounts.pyth_price_feed, ctx.accounts.fallback_price_feed.as_ref(), market)?.price;
         
-        // Find user position
-        let position_index_opt = market.user_positions
+        // Take the order off the user's list once its trigger is reached
+        let trigger_orders = &mut ctx.accounts.trigger_orders;
+        let index = trigger_orders
+            .orders
             .iter()
-            .position(|(pubkey, _)| *pubkey == liquidate_user);
+            .position(|order| order.id == order_id)
+            .ok_or(ErrorCode::TriggerOrderNotFound)?;
+        let trigger_order = trigger_orders.orders[index];
+        require!(trigger_order.is_triggered(oracle_price), ErrorCode::TriggerNotReached);
+        trigger_orders.orders.remove(index);
         
-        let position_index = position_index_opt.ok_or(ErrorCode::PositionNotFound)?;
-            
-        // Get asset parameters from registry
-        let asset_id = &market.asset_id;
-        let registry = &ctx.accounts.registry;
+        market.sync_asset_params(&ctx.accounts.registry)?;
+        market.accrue_funding(timestamp)?;
+        let market_key = market.key();
         
-        let asset_opt = registry.supported_assets
-            .iter()
-            .find(|(id, _)| id == asset_id);
-            
-        let (maintenance_margin_ratio, liquidation_fee) = match asset_opt {
-            Some((_, asset)) => (asset.maintenance_margin_ratio, asset.liquidation_fee),
-            None => return Err(ErrorCode::AssetNotAvailable.into()),
-        };
+        let position = &mut ctx.accounts.position;
+        let margin_account = &mut ctx.accounts.margin_account;
+        let side = trigger_order.side;
         
-        // Check position before moving it
-        {
-            let (_, position) = &market.user_positions[position_index];
-            
-            // Calculate position value and required margin
-            let position_value = position.notional_value(oracle_price);
-            let required_margin = position_value * maintenance_margin_ratio as u64 / 10000;
-            
-            // Check if position is liquidatable
-            require!(
-                position.is_liquidatable(oracle_price, maintenance_margin_ratio),
-                ErrorCode::PositionNotLiquidatable
-            );
+        // Trigger orders only reduce the position; with nothing left to
+        // reduce the order is dropped and the keeper goes unpaid
+        if position.size == 0 || position.side == side {
+            emit!(TriggerOrderCancelled {
+                market: market_key,
+                user: trigger_user,
+                order_id,
+                timestamp,
+            });
+            return Ok(());
         }
+        let size = std::cmp::min(trigger_order.size, position.size);
         
-        // Get position details for liquidation
-        let position = &market.user_positions[position_index].1;
-        let position_side = position.side;
-        let position_size = position.size;
-        let position_value = position.notional_value(oracle_price);
-        let maintenance_margin = position_value * maintenance_margin_ratio as u64 / 10000;
-            
-        // Calculate liquidation fee
-        let fee_amount = position_value * liquidation_fee as u64 / 10000;
+        // The band may have moved away from a stop-limit since it was placed
+        if trigger_order.kind == TriggerOrderKind::StopLimit {
+            market.check_price_band(trigger_order.limit_price, oracle_price)?;
+        }
         
-        // Calculate remaining margin after liquidation
-        let remaining = if position.margin > fee_amount {
-            position.margin - fee_amount
-        } else {
-            0
+        // The order stops at its limit or the slot's allowed move, whichever
+        // comes first, and a stop-limit rests what's left there
+        market.start_slot(Clock::get()?.slot);
+        let limit_price = market.slot_limited_price(side, (trigger_order.limit_price > 0).then_some(trigger_order.limit_price))?;
+        let rest_price = limit_price.unwrap_or(trigger_order.limit_price);
+        
+        let book_order_id = market.next_order_id;
+        market.next_order_id += 1;
+        
+        let mut orderbook = ctx.accounts.orderbook.load_mut()?;
+        let match_result = orderbook.match_order(
+            &trigger_user,
+            side,
+            limit_price,
+            size,
+            SelfTradeBehavior::CancelMaker,
+            timestamp,
+        )?;
+        if trigger_order.kind != TriggerOrderKind::StopLimit {
+            require!(match_result.filled_size > 0, ErrorCode::NoLiquidity);
+        }
+        
+        // Includes the user's own resting orders crossed by the close
+        emit_removed_makers(market_key, &match_result, timestamp);
+        
+        let vaults = PerpVaults {
+            token_program: ctx.accounts.token_program.to_account_info(),
+            collateral_vault: ctx.accounts.collateral_vault.to_account_info(),
+            collateral_signer: ctx.accounts.collateral_signer.to_account_info(),
+            collateral_signer_bump: ctx.bumps.collateral_signer,
+            insurance_fund: ctx.accounts.insurance_fund.to_account_info(),
+            vault_signer: ctx.accounts.vault_signer.to_account_info(),
+        };
+        let trade = PerpTrade {
+            user: trigger_user,
+            side,
+            order_id: book_order_id,
+            client_id: trigger_order.id,
+            size,
+            pays_fees: true,
+            timestamp,
         };
+        let settlement = settle_perp_fills(
+            market,
+            &vaults,
+            ctx.remaining_accounts,
+            &trade,
+            position,
+            margin_account,
+            &match_result.fills,
+        )?;
+        let filled_size = settlement.filled_size;
         
-        // Transfer liquidation fee to liquidator
-        if fee_amount > 0 {
-            // Create PDA signer seeds
-            let market_key = market.key();
-            let seeds = &[
-                b"vault_signer".as_ref(),
-                market_key.as_ref(),
-                &[market.vault_signer_bump],
-            ];
-            let signer = &[&seeds[..]];
-            
-            token::transfer(
-                CpiContext::new_with_signer(
-                    ctx.accounts.token_program.to_account_info(),
-                    Transfer {
-                        from: ctx.accounts.quote_vault.to_account_info(),
-                        to: ctx.accounts.liquidator_quote_account.to_account_info(),
-                        authority: ctx.accounts.vault_signer.to_account_info(),
-                    },
-                    signer,
-                ),
-                fee_amount,
-            )?;
+        // Losses the collateral can't cover are for liquidation, not a stop
+        require!(settlement.bad_debt == 0, ErrorCode::InsufficientMargin);
+        
+        if let Some(last_fill) = match_result.fills.last() {
+            let mark_price = orderbook.mid_price(timestamp).unwrap_or(last_fill.price);
+            record_perp_trade(market, mark_price, last_fill.price, oracle_price, timestamp)?;
         }
         
-        // Transfer remaining collateral to the user
-        if remaining > 0 {
-            // Create PDA signer seeds
-            let market_key = market.key();
-            let seeds = &[
-                b"vault_signer".as_ref(),
-                market_key.as_ref(),
-                &[market.vault_signer_bump],
-            ];
-            let signer = &[&seeds[..]];
+        // Stop-limit orders rest whatever didn't fill as a reduce-only limit order
+        let mut rested_size = 0;
+        if trigger_order.kind == TriggerOrderKind::StopLimit && !match_result.taker_cancelled {
+            rested_size = size - filled_size;
+        }
+        if rested_size > 0 {
+            let resting_order = Order::new(
+                book_order_id,
+                trigger_order.id,
+                trigger_user,
+                side,
+                rest_price,
+                rested_size,
+                TIME_IN_FORCE_GOOD_TILL_CANCEL,
+                0,
+                true,
+                false,
+            );
+            orderbook.insert_order(&resting_order)?;
             
-            token::transfer(
-                CpiContext::new_with_signer(
-                    ctx.accounts.token_program.to_account_info(),
-                    Transfer {
-                        from: ctx.accounts.quote_vault.to_account_info(),
-                        to: ctx.accounts.user_quote_account.to_account_info(),
-                        authority: ctx.accounts.vault_signer.to_account_info(),
-                    },
-                    signer,
-                ),
-                remaining,
-            )?;
+            if side == Side::Bid {
+                emit!(BidOrderAdded {
+                    market: market_key,
+                    order_id: book_order_id,
+                    client_id: trigger_order.id,
+                    user: trigger_user,
+                    price: rest_price,
+                    size: rested_size,
+                    reduce_only: true,
+                    post_only: false,
+                    timestamp,
+                });
+            } else {
+                emit!(AskOrderAdded {
+                    market: market_key,
+                    order_id: book_order_id,
+                    client_id: trigger_order.id,
+                    user: trigger_user,
+                    price: rest_price,
+                    size: rested_size,
+                    reduce_only: true,
+                    post_only: false,
+                    timestamp,
+                });
+            }
         }
         
-        // Update market open interest
-        match position_side {
-            Side::Bid => market.open_interest_long -= position_size,
-            Side::Ask => market.open_interest_short -= position_size,
+        // Protocol fees and the keeper fee come out of collateral in the collateral vault
+        if settlement.protocol_fee > 0 {
+            let fee = collect_perp_fee(
+                market,
+                &vaults,
+                ctx.accounts.fee_vault.to_account_info(),
+                settlement.protocol_fee,
+            )?;
+            market.accrued_fees = market.accrued_fees.checked_add(fee).ok_or(ErrorCode::MathOverflow)?;
         }
         
-        // Remove the position
-        market.user_positions.remove(position_index);
+        let keeper_fee = std::cmp::min(market.trigger_order_fee, margin_account.collateral);
+        margin_account.collateral = margin_account.collateral
+            .checked_sub(keeper_fee)
+            .ok_or(ErrorCode::MathOverflow)?;
+        pay_from_collateral(&vaults, ctx.accounts.keeper_quote_account.to_account_info(), keeper_fee)?;
         
-        emit!(PositionLiquidated {
-            market: market.key(),
-            user: liquidate_user,
-            liquidator: liquidator_key,
-            side: position_side,
-            size: position_size,
-            position_value,
-            maintenance_margin,
-            liquidation_fee: fee_amount,
-            remaining,
+        emit!(PositionUpdated {
+            market: market_key,
+            user: trigger_user,
+            side: position.side,
+            size: position.size,
+            collateral: margin_account.collateral,
+            entry_price: position.entry_price,
+            leverage: position.leverage,
+            realized_pnl: position.realized_pnl,
+            liquidation_price: position.liquidation_price,
+            timestamp,
+        });
+        
+        emit!(TriggerOrderExecuted {
+            market: market_key,
+            user: trigger_user,
+            keeper: keeper_key,
+            order_id,
+            kind: trigger_order.kind,
+            side,
+            trigger_price: trigger_order.trigger_price,
             oracle_price,
+            filled_size,
+            rested_size,
+            keeper_fee,
             timestamp,
         });
         
         Ok(())
     }
 
+    pub fn init_collateral_vault(_ctx: Context<InitCollateralVault>) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn init_margin_account(ctx: Context<InitMarginAccount>) -> Result<()> {
+        let margin_account = &mut ctx.accounts.margin_account;
+        margin_account.owner = ctx.accounts.user.key();
+        margin_account.bump = ctx.bumps.margin_account;
+        margin_account.collateral_mint = ctx.accounts.collateral_mint.key();
+        margin_account.collateral = 0;
+        margin_account.positions = Vec::new();
+        
+        Ok(())
+    }
+
+    pub fn init_position(ctx: Context<InitPosition>) -> Result<()> {
+        let market = &ctx.accounts.market;
+        
+        // Verify this is a perpetual market
+        require!(market.is_perpetual, ErrorCode::NotPerpetualMarket);
+        
+        // Register the position with the user's margin account
+        let margin_account = &mut ctx.accounts.margin_account;
+        require!(
+            margin_account.positions.len() < MAX_MARGIN_POSITIONS,
+            ErrorCode::TooManyPositions
+        );
+        margin_account.positions.push(ctx.accounts.position.key());
+        
+        let position = &mut ctx.accounts.position;
+        position.market = market.key();
+        position.owner = ctx.accounts.user.key();
+        position.bump = ctx.bumps.position;
+        position.side = Side::Bid; // Default side, will be set properly when a trade occurs
+        position.size = 0;
+        position.entry_price = 0;
+        position.leverage = 1;
+        position.last_funding_index = market.cumulative_funding_at(Clock::get()?.unix_timestamp as u64)?;
+        position.last_socialized_loss_index = market.socialized_loss_index(Side::Bid);
+        position.realized_pnl = 0;
+        position.liquidation_price = 0;
+        position.last_updated_timestamp = Clock::get()?.unix_timestamp as u64;
+        
+        Ok(())
+    }
+
+    pub fn close_position(ctx: Context<ClosePosition>) -> Result<()> {
+        let user_key = ctx.accounts.user.key();
+        let position = &mut ctx.accounts.position;
+        
+        // Only flat positions without resting orders can be closed
+        let open_orders = ctx.accounts.orderbook.load()?.open_orders(&user_key);
+        require!(
+            position.is_empty() && open_orders.is_empty(),
+            ErrorCode::PositionNotEmpty
+        );
+        
+        // Settle any leftover PnL and unregister the position. A loss the
+        // collateral can't cover stays with the position until deposited for.
+        let margin_account = &mut ctx.accounts.margin_account;
+        require!(margin_account.settle_pnl(position)? == 0, ErrorCode::InsufficientMargin);
+        let position_key = position.key();
+        margin_account.positions.retain(|key| *key != position_key);
+        
+        Ok(())
+    }
+
     pub fn deposit_collateral(
         ctx: Context<ManageCollateral>,
         amount: u64
     ) -> Result<()> {
-        let market = &mut ctx.accounts.market;
         let user_key = ctx.accounts.user.key();
         let timestamp = Clock::get()?.unix_timestamp as u64;
         
-        // Verify this is a perpetual market
-        require!(market.is_perpetual, ErrorCode::NotPerpetualMarket);
-        
         // Verify amount is positive
         require!(amount > 0, ErrorCode::InvalidParameters);
         
-        // Transfer tokens from user to vault
+        // Transfer tokens from user to the collateral vault
         token::transfer(
             CpiContext::new(
                 ctx.accounts.token_program.to_account_info(),
                 Transfer {
                     from: ctx.accounts.user_quote_account.to_account_info(),
-                    to: ctx.accounts.quote_vault.to_account_info(),
+                    to: ctx.accounts.collateral_vault.to_account_info(),
                     authority: ctx.accounts.user.to_account_info(),
                 },
             ),
             amount,
         )?;
         
-        // Find user position or create new one
-        let position_index = market.user_positions
-            .iter()
-            .position(|(pubkey, _)| *pubkey == user_key);
+        // Credit the user's margin account
+        let margin_account = &mut ctx.accounts.margin_account;
+        margin_account.collateral = margin_account.collateral
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
         
-        if let Some(idx) = position_index {
-            // Update existing position
-            market.user_positions[idx].1.margin += amount;
-            let total_margin = market.user_positions[idx].1.margin;
-            
-            // Update liquidation price if position is active
-            if market.user_positions[idx].1.size > 0 {
-                if let Some(pyth_account) = &ctx.accounts.pyth_price_feed {
-                    // Get oracle price
-                 let oracle_price = get_pyth_price(pyth_account, &ctx.accounts.market)?;
-                    
-                    // Get maintenance margin ratio from registry
-                    let asset_id = &market.asset_id;
-                    let asset_opt = ctx.accounts.registry.supported_assets
-                        .iter()
-                        .find(|(id, _)| id == asset_id);
-                        
-                    let maintenance_margin_ratio = match asset_opt {
-                        Some((_, asset)) => asset.maintenance_margin_ratio,
-                        None => return Err(ErrorCode::AssetNotAvailable.into()),
-                    };
-                    
-                    market.user_positions[idx].1.update_liquidation_price(maintenance_margin_ratio);
-                }
-            }
-            
-            emit!(CollateralDeposited {
-                market: market.key(),
-                user: user_key,
-                amount,
-                total_margin,
-                timestamp,
-            });
-        } else {
-            // Create new position with default values
-            let side = Side::Bid; // Default side, will be set properly when a trade occurs
-            let new_position = Position::new(side, amount);
-            
-            market.user_positions.push((user_key, new_position));
-            
-            emit!(CollateralDeposited {
-                market: market.key(),
-                user: user_key,
-                amount,
-                total_margin: amount,
-                timestamp,
-            });
-        }
+        emit!(CollateralDeposited {
+            margin_account: margin_account.key(),
+            user: user_key,
+            amount,
+            total_collateral: margin_account.collateral,
+            timestamp,
+        });
         
         Ok(())
     }
-    pub fn withdraw_collateral(
-        ctx: Context<ManageCollateral>,
+
+    // Every position in the margin account must be passed in the remaining
+    // accounts with its market's orderbook, and unless it is empty with no
+    // resting orders, its market and that market's primary price update; the
+    // withdrawal fails if any of them is missing.
+    pub fn withdraw_collateral<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ManageCollateral<'info>>,
         amount: u64
     ) -> Result<()> {
-      let market = &mut ctx.accounts.market;
         let user_key = ctx.accounts.user.key();
         let timestamp = Clock::get()?.unix_timestamp as u64;
+        let margin_account = &mut ctx.accounts.margin_account;
         
-        // Verify this is a perpetual market
-        require!(market.is_perpetual, ErrorCode::NotPerpetualMarket);
-        
-        // Find user position
-        let position_index_opt = market.user_positions
-            .iter()
-            .position(|(pubkey, _)| *pubkey == user_key);
-        
-        let position_index = position_index_opt.ok_or(ErrorCode::PositionNotFound)?;
-        
-        // Prepare an immutable reference to market for use in get_pyth_price
-        let market_immut_ref = &*market;
-        
-        // Check if withdrawal is possible
-        {
-            let position = &market.user_positions[position_index].1;
-            require!(position.margin >= amount, ErrorCode::InsufficientMargin);
-            
-            // If position is active, check if withdrawal would trigger liquidation
-            if position.size > 0 {
-                // Check oracle price
-                if let Some(pyth_account) = &ctx.accounts.pyth_price_feed {
-                    let oracle_price = get_pyth_price(pyth_account, market_immut_ref)?;
-                    
-                    // Get asset parameters from registry
-                    let asset_id = &market.asset_id;
-                    let asset_opt = ctx.accounts.registry.supported_assets
-                        .iter()
-                        .find(|(id, _)| id == asset_id);
-                        
-                    let maintenance_margin_ratio = match asset_opt {
-                        Some((_, asset)) => asset.maintenance_margin_ratio,
-                        None => return Err(ErrorCode::AssetNotAvailable.into()),
-                    };
-                    
-                    // Calculate position value and required margin
-                    let position_value = position.notional_value(oracle_price);
-                    let required_margin = position_value * maintenance_margin_ratio as u64 / 10000;
-                    
-                    // Ensure remaining margin is sufficient
-                    require!(
-                        position.margin - amount >= required_margin,
-                        ErrorCode::WithdrawalWouldTriggerLiquidation
-                    );
-                } else {
-                    return Err(ErrorCode::InvalidPriceFeed.into());
-                }
-            }
-        }
+        require!(margin_account.collateral >= amount, ErrorCode::InsufficientMargin);
         
-        // Update position
-        let position = &mut market.user_positions[position_index].1;
-        position.margin -= amount;
+        // Value every position in the margin account, including unsettled
+        // funding, and make sure the equity left after the withdrawal still
+        // covers the initial requirement. Unrealized gains can't be withdrawn.
+        let summary = cross_margin_summary(margin_account, ctx.remaining_accounts, None)?;
+        require!(
+            summary.withdrawable_equity - amount as i128 >= summary.initial_margin as i128,
+            ErrorCode::WithdrawalWouldTriggerLiquidation
+        );
         
-        // Update liquidation price
-        if position.size > 0 {
-            // Get maintenance margin ratio
-            let asset_id = &market.asset_id;
-            let asset_opt = ctx.accounts.registry.supported_assets
-                .iter()
-                .find(|(id, _)| id == asset_id);
-                
-            let maintenance_margin_ratio = match asset_opt {
-                Some((_, asset)) => asset.maintenance_margin_ratio,
-                None => return Err(ErrorCode::AssetNotAvailable.into()),
-            };
-            
-            position.update_liquidation_price(maintenance_margin_ratio);
-        }
+        margin_account.collateral = margin_account.collateral
+            .checked_sub(amount)
+            .ok_or(ErrorCode::InsufficientMargin)?;
+        let remaining_collateral = margin_account.collateral;
         
         // Create PDA signer seeds for transfer
-        let market_key = market.key();
         let seeds = &[
-            b"vault_signer".as_ref(),
-            market_key.as_ref(),
-            &[market.vault_signer_bump],
+            b"collateral_signer".as_ref(),
+            &[ctx.bumps.collateral_signer],
         ];
         let signer = &[&seeds[..]];
         
-        // Transfer tokens from vault to user
+        // Transfer tokens from the collateral vault to user
         token::transfer(
             CpiContext::new_with_signer(
                 ctx.accounts.token_program.to_account_info(),
                 Transfer {
-                    from: ctx.accounts.quote_vault.to_account_info(),
+                    from: ctx.accounts.collateral_vault.to_account_info(),
                     to: ctx.accounts.user_quote_account.to_account_info(),
-                    authority: ctx.accounts.vault_signer.to_account_info(),
+                    authority: ctx.accounts.collateral_signer.to_account_info(),
                 },
                 signer,
             ),
             amount,
         )?;
         
-        let remaining_margin = position.margin;
-        
         emit!(CollateralWithdrawn {
-            market: market.key(),
+            margin_account: ctx.accounts.margin_account.key(),
             user: user_key,
             amount,
-            remaining_margin,
+            remaining_collateral,
             timestamp,
         });
         
-        // If position is empty (no size) and no margin left, remove the position
-        if position.size == 0 && position.margin == 0 {
-            market.user_positions.remove(position_index);
-        }
-        
         Ok(())
     }
 
     // Additional utility instruction to cancel all orders for a user
     pub fn cancel_all_orders(ctx: Context<CancelOrder>) -> Result<()> {
         let market = &ctx.accounts.market;
-        let orderbook = &mut ctx.accounts.orderbook;
+        let mut orderbook = ctx.accounts.orderbook.load_mut()?;
         let user_key = ctx.accounts.user.key();
         let timestamp = Clock::get()?.unix_timestamp as u64;
         
-        // Find all orders for this user
-        let user_orders = orderbook.find_orders_for_user(&user_key);
+        // Remove all orders for this user
+        let cancelled = orderbook.cancel_user_orders(&user_key)?;
         
         // No orders found
-        if user_orders.is_empty() {
+        if cancelled.is_empty() {
             return Ok(());
         }
         
-        // Process bid cancellations
-        let mut bid_indices = Vec::new();
+        // Locked tokens to return for spot markets
+        let mut base_refund = 0;
+        let mut quote_refund = 0;
         
-        for (side, price, price_idx, order_idx) in user_orders.iter().filter(|(s, _, _, _)| *s == Side::Bid) {
-            // Get order details before removal
-            let order = &orderbook.bids[*price_idx].1[*order_idx];
-            let order_id = order.id;
-            let client_id = order.client_id;
-            let remaining_size = order.remaining_size;
-            let reduce_only = order.reduce_only;
+        for order in cancelled.iter() {
+            match order.side {
+                Side::Bid => quote_refund += math::notional(order.remaining_size, order.price)?,
+                Side::Ask => base_refund += order.remaining_size,
+            }
             
             // Emit cancel event
             emit!(OrderCancelled {
                 market: market.key(),
-                order_id,
-                client_id,
+                order_id: order.id,
+                client_id: order.client_id,
                 user: user_key,
-                side: *side,
-                price: *price,
-                remaining_size,
-                reduce_only,
+                side: order.side,
+                price: order.price,
+                remaining_size: order.remaining_size,
+                reduce_only: order.reduce_only,
                 timestamp,
             });
-            
-            // Store indices for later removal
-            bid_indices.push((*price_idx, *order_idx));
         }
         
-        // Remove bid orders from highest to lowest index
-        bid_indices.sort_by(|a, b| b.cmp(a));
-        
-        for (price_idx, order_idx) in bid_indices {
-            orderbook.bids[price_idx].1.remove(order_idx);
+        // For spot markets, return locked tokens
+        if !market.is_perpetual && (base_refund > 0 || quote_refund > 0) {
+            let vault_signer = ctx.accounts.vault_signer.as_ref().ok_or(ErrorCode::InvalidTokenAccount)?;
             
-            // If price level is empty, remove it
-            if orderbook.bids[price_idx].1.is_empty() {
-                orderbook.bids.remove(price_idx);
+            // Create PDA signer seeds
+            let market_key = market.key();
+            let seeds = &[
+                b"vault_signer".as_ref(),
+                market_key.as_ref(),
+                &[market.vault_signer_bump],
+            ];
+            let signer = &[&seeds[..]];
+            
+            if base_refund > 0 {
+                let base_vault = ctx.accounts.base_vault.as_ref().ok_or(ErrorCode::InvalidTokenAccount)?;
+                let user_base_account = ctx.accounts.user_base_account.as_ref().ok_or(ErrorCode::InvalidTokenAccount)?;
+                
+                token::transfer(
+                    CpiContext::new_with_signer(
+                        ctx.accounts.token_program.to_account_info(),
+                        Transfer {
+                            from: base_vault.to_account_info(),
+                            to: user_base_account.to_account_info(),
+                            authority: vault_signer.to_account_info(),
+                        },
+                        signer,
+                    ),
+                    base_refund,
+                )?;
+            }
+            
+            if quote_refund > 0 {
+                let quote_vault = ctx.accounts.quote_vault.as_ref().ok_or(ErrorCode::InvalidTokenAccount)?;
+                let user_quote_account = ctx.accounts.user_quote_account.as_ref().ok_or(ErrorCode::InvalidTokenAccount)?;
+                
+                token::transfer(
+                    CpiContext::new_with_signer(
+                        ctx.accounts.token_program.to_account_info(),
+                        Transfer {
+                            from: quote_vault.to_account_info(),
+                            to: user_quote_account.to_account_info(),
+                            authority: vault_signer.to_account_info(),
+                        },
+                        signer,
+                    ),
+                    quote_refund,
+                )?;
             }
         }
         
-        // Process ask cancellations
-        let mut ask_indices = Vec::new();
+        Ok(())
+    }
+    // Pay out what a user's spot orders have earned from fills
+    pub fn init_claimable_balances(ctx: Context<InitClaimableBalances>) -> Result<()> {
+        let claimable_balances = &mut ctx.accounts.claimable_balances;
+        claimable_balances.market = ctx.accounts.market.key();
+        claimable_balances.owner = ctx.accounts.user.key();
+        claimable_balances.bump = ctx.bumps.claimable_balances;
+        claimable_balances.base_claimable = 0;
+        claimable_balances.quote_claimable = 0;
         
-        for (side, price, price_idx, order_idx) in user_orders.iter().filter(|(s, _, _, _)| *s == Side::Ask) {
-            // Get order details before removal
-            let order = &orderbook.asks[*price_idx].1[*order_idx];
-            let order_id = order.id;
-            let client_id = order.client_id;
-            let remaining_size = order.remaining_size;
-            let reduce_only = order.reduce_only;
-            
-            // Emit cancel event
-            emit!(OrderCancelled {
-                market: market.key(),
-                order_id,
-                client_id,
-                user: user_key,
-                side: *side,
-                price: *price,
-                remaining_size,
-                reduce_only,
-                timestamp,
-            });
-            
-            // Store indices for later removal
-            ask_indices.push((*price_idx, *order_idx));
-        }
+        Ok(())
+    }
+
+    pub fn claim_balances(ctx: Context<ClaimBalances>) -> Result<()> {
+        let market = &ctx.accounts.market;
+        let user_key = ctx.accounts.user.key();
+        let timestamp = Clock::get()?.unix_timestamp as u64;
         
-        // Remove ask orders from highest to lowest index
-        ask_indices.sort_by(|a, b| b.cmp(a));
+        require!(!market.is_perpetual, ErrorCode::InvalidParameters);
         
-        for (price_idx, order_idx) in ask_indices {
-            orderbook.asks[price_idx].1.remove(order_idx);
-            
-            // If price level is empty, remove it
-            if orderbook.asks[price_idx].1.is_empty() {
-                orderbook.asks.remove(price_idx);
-            }
+        let (base_amount, quote_amount) = ctx.accounts.claimable_balances.take();
+        
+        // Create PDA signer seeds
+        let market_key = market.key();
+        let seeds = &[
+            b"vault_signer".as_ref(),
+            market_key.as_ref(),
+            &[market.vault_signer_bump],
+        ];
+        let signer = &[&seeds[..]];
+        
+        if base_amount > 0 {
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.base_vault.to_account_info(),
+                        to: ctx.accounts.user_base_account.to_account_info(),
+                        authority: ctx.accounts.vault_signer.to_account_info(),
+                    },
+                    signer,
+                ),
+                base_amount,
+            )?;
         }
         
-        // For spot markets, return locked tokens
-        if !market.is_perpetual {
-            // Token handling would go here in a complete implementation
+        if quote_amount > 0 {
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.quote_vault.to_account_info(),
+                        to: ctx.accounts.user_quote_account.to_account_info(),
+                        authority: ctx.accounts.vault_signer.to_account_info(),
+                    },
+                    signer,
+                ),
+                quote_amount,
+            )?;
         }
         
+        emit!(BalancesClaimed {
+            market: market_key,
+            user: user_key,
+            base_amount,
+            quote_amount,
+            timestamp,
+        });
+        
         Ok(())
     }
-    
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn perp_market() -> Market {
+        Market {
+            authority: Pubkey::default(),
+            base_mint: Pubkey::default(),
+            quote_mint: Pubkey::default(),
+            base_vault: Pubkey::default(),
+            quote_vault: Pubkey::default(),
+            vault_signer_bump: 0,
+            registry: Pubkey::default(),
+            min_base_order_size: 1,
+            tick_size: 1,
+            taker_fee_bps: 0,
+            maker_rebate_bps: 0,
+            next_order_id: 0,
+            next_client_id: 0,
+            status: MarketStatus::Active,
+            market_name: String::new(),
+            market_symbol: String::new(),
+            asset_id: String::new(),
+            is_perpetual: true,
+            settle_with_usdc: true,
+            last_funding_timestamp: 0,
+            last_oracle_price: 100_000_000,
+            oracle_price_offset: 0,
+            mark_price_twap: 0,
+            open_interest_long: 0,
+            open_interest_short: 0,
+            cumulative_funding_long: 0,
+            cumulative_funding_short: 0,
+            funding_interval: 3_600,
+            max_leverage: 20,
+            funding_rate: 0,
+            last_funding_accrual: 0,
+            oracle_price_twap: 0,
+            last_mark_price: 0,
+            last_twap_timestamp: 0,
+            funding_period: 86_400,
+            max_funding_rate: DEFAULT_MAX_FUNDING_RATE,
+            oracle_feed_id: [0; 32],
+            max_oracle_age: 60,
+            max_oracle_confidence: DEFAULT_MAX_ORACLE_CONFIDENCE,
+            secondary_oracle_feed_id: [0; 32],
+            book_twap_fallback: false,
+            max_oracle_deviation: DEFAULT_MAX_ORACLE_DEVIATION,
+            price_band: DEFAULT_PRICE_BAND,
+            max_slot_price_move: DEFAULT_MAX_SLOT_PRICE_MOVE,
+            max_mark_deviation: DEFAULT_MAX_MARK_DEVIATION,
+            last_trade_price: 0,
+            last_trade_slot: 0,
+            slot_open_price: 0,
+            fee_vault: Pubkey::default(),
+            accrued_fees: 0,
+            trigger_order_fee: DEFAULT_TRIGGER_ORDER_FEE,
+            maintenance_margin_ratio: 500,
+            liquidation_fee: 100,
+            asset_max_leverage: 20,
+            funding_rate_multiplier: 10_000,
+            asset_active: true,
+            liquidation_close_factor: DEFAULT_LIQUIDATION_CLOSE_FACTOR,
+            liquidation_max_slippage: DEFAULT_LIQUIDATION_MAX_SLIPPAGE,
+            insurance_fund: Pubkey::default(),
+            insurance_fund_balance: 0,
+            insurance_fee_share: DEFAULT_INSURANCE_FEE_SHARE,
+            socialized_loss_index_long: 0,
+            socialized_loss_index_short: 0,
+        }
+    }
+
+    fn position() -> Position {
+        Position {
+            market: Pubkey::default(),
+            owner: Pubkey::new_unique(),
+            bump: 0,
+            side: Side::Bid,
+            size: 0,
+            entry_price: 0,
+            leverage: 10,
+            last_funding_index: 0,
+            last_socialized_loss_index: 0,
+            realized_pnl: 0,
+            liquidation_price: 0,
+            last_updated_timestamp: 0,
+        }
+    }
+
+    fn trigger_order(kind: TriggerOrderKind, side: Side, trigger_price: u64) -> TriggerOrder {
+        TriggerOrder {
+            id: 0,
+            kind,
+            side,
+            trigger_price,
+            limit_price: 0,
+            size: 1_000_000,
+            timestamp: 0,
+        }
+    }
+
+    // Fill `size` between a taker and a maker on the other side
+    fn trade(market: &mut Market, taker: &mut Position, maker: &mut Position, side: Side, price: u64, size: u64) {
+        let maker_side = match side {
+            Side::Bid => Side::Ask,
+            Side::Ask => Side::Bid,
+        };
+        market.apply_fill(taker, side, price, size, 0).unwrap();
+        market.apply_fill(maker, maker_side, price, size, 0).unwrap();
+    }
+
+    fn long_size(positions: &[&Position]) -> u64 {
+        positions.iter().filter(|p| p.side == Side::Bid).map(|p| p.size).sum()
+    }
+
+    #[test]
+    fn open_interest_balances_across_fills_and_flips() {
+        let mut market = perp_market();
+        let (mut a, mut b, mut c) = (position(), position(), position());
+
+        // A opens 3 long against B
+        trade(&mut market, &mut a, &mut b, Side::Bid, 100_000_000, 3_000_000);
+        assert_eq!((market.open_interest_long, market.open_interest_short), (3_000_000, 3_000_000));
+
+        // A sells 5 to B, flipping both
+        trade(&mut market, &mut a, &mut b, Side::Ask, 110_000_000, 5_000_000);
+        assert_eq!((a.side, a.size, a.entry_price), (Side::Ask, 2_000_000, 110_000_000));
+        assert_eq!((b.side, b.size, b.entry_price), (Side::Bid, 2_000_000, 110_000_000));
+        assert_eq!((a.realized_pnl, b.realized_pnl), (30_000_000, -30_000_000));
+        assert_eq!((market.open_interest_long, market.open_interest_short), (2_000_000, 2_000_000));
+
+        // C buys 1 from B, reducing B and opening C
+        trade(&mut market, &mut c, &mut b, Side::Bid, 120_000_000, 1_000_000);
+        assert_eq!((market.open_interest_long, market.open_interest_short), (2_000_000, 2_000_000));
+
+        // A buys back 1 from C, closing C
+        trade(&mut market, &mut a, &mut c, Side::Bid, 115_000_000, 1_000_000);
+        assert!(c.is_empty());
+        assert_eq!((market.open_interest_long, market.open_interest_short), (1_000_000, 1_000_000));
+        assert_eq!(market.open_interest_long, long_size(&[&a, &b, &c]));
+    }
+
+    #[test]
+    fn funding_accrues_across_a_rate_change() {
+        let mut market = perp_market();
+        let mut long = position();
+        let mut short = position();
+        trade(&mut market, &mut long, &mut short, Side::Bid, 100_000_000, 1_000_000);
+
+        // 10 bps per interval for half an interval, then -20 bps for a full one
+        market.funding_rate = 10 * math::FUNDING_PRECISION as i64;
+        assert_eq!(market.cumulative_funding_at(1_800).unwrap(), 50_000_000_000);
+        // Previewing doesn't accrue
+        assert_eq!(market.cumulative_funding_long, 0);
+        market.accrue_funding(1_800).unwrap();
+        market.funding_rate = -20 * math::FUNDING_PRECISION as i64;
+        market.accrue_funding(5_400).unwrap();
+
+        assert_eq!(market.cumulative_funding_long, -150_000_000_000);
+        assert_eq!(market.cumulative_funding_short, 150_000_000_000);
+        assert_eq!(market.last_funding_accrual, 5_400);
+        // Accruing again at the same time changes nothing
+        market.accrue_funding(5_400).unwrap();
+        assert_eq!(market.cumulative_funding_long, -150_000_000_000);
+
+        // Net 15 bps of $100 flows from the shorts to the longs
+        assert_eq!(long.pending_funding(market.cumulative_funding_long).unwrap(), 150_000);
+        assert_eq!(short.pending_funding(market.cumulative_funding_long).unwrap(), -150_000);
+
+        // A fill settles funding on the old size before changing it
+        market.apply_fill(&mut long, Side::Bid, 100_000_000, 1_000_000, 5_400).unwrap();
+        assert_eq!(long.realized_pnl, 150_000);
+        assert_eq!(long.pending_funding(market.cumulative_funding_long).unwrap(), 0);
+    }
+
+    #[test]
+    fn socialized_losses_fall_on_the_other_side() {
+        let mut market = perp_market();
+        let mut long = position();
+        let mut short = position();
+        trade(&mut market, &mut long, &mut short, Side::Bid, 100_000_000, 4_000_000);
+
+        // A bankrupt long's loss is charged to the shorts, rounded up
+        assert_eq!(market.socialize_loss(Side::Bid, 10_000_001).unwrap(), 10_000_001);
+        assert_eq!(market.socialized_loss_index_short, 2_500_001);
+        assert_eq!(market.socialized_loss_index_long, 0);
+        assert_eq!(short.pending_socialized_loss(market.socialized_loss_index_short).unwrap(), 10_000_004);
+        assert_eq!(long.pending_socialized_loss(market.socialized_loss_index_long).unwrap(), 0);
+
+        // Nothing can be charged to a side without open interest
+        market.open_interest_long = 0;
+        assert_eq!(market.socialize_loss(Side::Ask, 1_000_000).unwrap(), 0);
+        assert_eq!(market.socialized_loss_index_long, 0);
+    }
+
+    #[test]
+    fn twaps_weight_observations_by_how_long_they_stood() {
+        let mut market = perp_market();
+        market.update_twaps(100_000_000, 100_000_000, 0).unwrap();
+        // A new mark only counts once time passes after it
+        market.update_twaps(120_000_000, 100_000_000, 1_800).unwrap();
+        assert_eq!(market.mark_price_twap, 100_000_000);
+        market.update_twaps(120_000_000, 100_000_000, 3_600).unwrap();
+        assert_eq!(market.mark_price_twap, 110_000_000);
+        assert_eq!(market.oracle_price_twap, 100_000_000);
+
+        // A 10% deviation is inside the default 20% breaker
+        assert!(!market.check_mark_deviation().unwrap());
+        assert_eq!(market.status, MarketStatus::Active);
+
+        market.max_mark_deviation = 500;
+        assert!(market.check_mark_deviation().unwrap());
+        assert_eq!(market.status, MarketStatus::Paused);
+        // Only an active market trips
+        assert!(!market.check_mark_deviation().unwrap());
+    }
+
+    #[test]
+    fn slot_price_limit_anchors_at_the_last_trade_before_the_slot() {
+        let mut market = perp_market();
+        // Nothing to anchor to before the first trade
+        market.start_slot(5);
+        assert_eq!(market.slot_price_limit(Side::Bid).unwrap(), None);
+
+        market.last_trade_price = 100_000_000;
+        market.start_slot(6);
+        assert_eq!(market.slot_price_limit(Side::Bid).unwrap(), Some(110_000_000));
+        assert_eq!(market.slot_price_limit(Side::Ask).unwrap(), Some(90_000_000));
+
+        // Trades within the slot don't move the anchor
+        market.last_trade_price = 109_000_000;
+        market.start_slot(6);
+        assert_eq!(market.slot_price_limit(Side::Bid).unwrap(), Some(110_000_000));
+        market.start_slot(7);
+        assert_eq!(market.slot_price_limit(Side::Bid).unwrap(), Some(119_900_000));
+
+        market.max_slot_price_move = 0;
+        assert_eq!(market.slot_price_limit(Side::Ask).unwrap(), None);
+        assert_eq!(market.slot_limited_price(Side::Ask, Some(80_000_000)).unwrap(), Some(80_000_000));
+        assert_eq!(market.slot_limited_price(Side::Ask, None).unwrap(), None);
+    }
+
+    #[test]
+    fn slot_limited_price_clamps_limits_onto_the_tick_grid() {
+        let mut market = perp_market();
+        market.tick_size = 1_000_000;
+        market.last_trade_price = 100_500_000;
+        market.start_slot(1);
+
+        // The 10% move ends at 110.55 and 90.45, rounded towards the slot open
+        assert_eq!(market.slot_limited_price(Side::Bid, None).unwrap(), Some(110_000_000));
+        assert_eq!(market.slot_limited_price(Side::Ask, None).unwrap(), Some(91_000_000));
+        // Limits inside the move stand, ones past it are pulled in
+        assert_eq!(market.slot_limited_price(Side::Bid, Some(105_000_000)).unwrap(), Some(105_000_000));
+        assert_eq!(market.slot_limited_price(Side::Bid, Some(120_000_000)).unwrap(), Some(110_000_000));
+        assert_eq!(market.slot_limited_price(Side::Ask, Some(95_000_000)).unwrap(), Some(95_000_000));
+        assert_eq!(market.slot_limited_price(Side::Ask, Some(80_000_000)).unwrap(), Some(91_000_000));
+    }
+
+    #[test]
+    fn price_band_bounds_limits_around_the_oracle() {
+        let mut market = perp_market();
+        market.price_band = 1_000;
+        assert!(market.check_price_band(90_000_000, 100_000_000).is_ok());
+        assert!(market.check_price_band(110_000_000, 100_000_000).is_ok());
+        assert!(market.check_price_band(89_999_999, 100_000_000).is_err());
+        assert!(market.check_price_band(110_000_001, 100_000_000).is_err());
+        // A limit that was in the band can fall out of it as the oracle moves
+        assert!(market.check_price_band(110_000_000, 99_000_000).is_err());
+    }
+
+    #[test]
+    fn trigger_orders_fire_against_or_for_the_position() {
+        // Closing a long: stops fire on a fall, take-profits on a rise
+        for kind in [TriggerOrderKind::StopMarket, TriggerOrderKind::StopLimit] {
+            let stop = trigger_order(kind, Side::Ask, 90_000_000);
+            assert!(stop.is_triggered(90_000_000));
+            assert!(stop.is_triggered(80_000_000));
+            assert!(!stop.is_triggered(91_000_000));
+        }
+        let take_profit = trigger_order(TriggerOrderKind::TakeProfit, Side::Ask, 110_000_000);
+        assert!(take_profit.is_triggered(110_000_000));
+        assert!(!take_profit.is_triggered(109_000_000));
+
+        // Closing a short: the other way round
+        for kind in [TriggerOrderKind::StopMarket, TriggerOrderKind::StopLimit] {
+            let stop = trigger_order(kind, Side::Bid, 110_000_000);
+            assert!(stop.is_triggered(110_000_000));
+            assert!(stop.is_triggered(120_000_000));
+            assert!(!stop.is_triggered(109_000_000));
+        }
+        let take_profit = trigger_order(TriggerOrderKind::TakeProfit, Side::Bid, 90_000_000);
+        assert!(take_profit.is_triggered(90_000_000));
+        assert!(!take_profit.is_triggered(91_000_000));
+    }
+
+    #[test]
+    fn adl_queue_keeps_the_best_positions_on_each_side() {
+        let mut queue = AdlQueue {
+            market: Pubkey::default(),
+            bump: 0,
+            longs: Vec::new(),
+            shorts: Vec::new(),
+        };
+        let positions: Vec<Pubkey> = (0..=MAX_ADL_QUEUE_ENTRIES).map(|_| Pubkey::new_unique()).collect();
+        let scores = |entries: &Vec<AdlQueueEntry>| entries.iter().map(|entry| entry.score).collect::<Vec<_>>();
+
+        // Fill the long side past its limit, worst first
+        for (score, position) in positions.iter().enumerate() {
+            queue.update(*position, Side::Bid, score as u64 + 1);
+        }
+        assert_eq!(queue.longs.len(), MAX_ADL_QUEUE_ENTRIES);
+        assert_eq!(queue.longs[0].position, positions[MAX_ADL_QUEUE_ENTRIES]);
+        assert!(queue.longs.windows(2).all(|pair| pair[0].score > pair[1].score));
+        // The lowest score fell off the end
+        assert!(!queue.longs.iter().any(|entry| entry.position == positions[0]));
+
+        // Rescoring moves an entry, and ties keep their order
+        queue.update(positions[5], Side::Bid, 17);
+        assert_eq!(queue.longs[0].position, positions[MAX_ADL_QUEUE_ENTRIES]);
+        assert_eq!(queue.longs[1].position, positions[5]);
+        assert_eq!(queue.longs.len(), MAX_ADL_QUEUE_ENTRIES);
+
+        // A flipped position moves sides, and one out of profit leaves
+        queue.update(positions[5], Side::Ask, 3);
+        assert_eq!(scores(&queue.shorts), vec![3]);
+        assert_eq!(queue.longs.len(), MAX_ADL_QUEUE_ENTRIES - 1);
+        queue.update(positions[5], Side::Ask, 0);
+        assert!(queue.shorts.is_empty());
+
+        // Below a full queue's tail nothing is added
+        for position in positions.iter().take(2) {
+            queue.update(*position, Side::Bid, 100);
+        }
+        queue.update(Pubkey::new_unique(), Side::Bid, 1);
+        assert_eq!(queue.longs.len(), MAX_ADL_QUEUE_ENTRIES);
+        assert_eq!(queue.longs.last().unwrap().score, 3);
+        queue.remove(&positions[0]);
+        assert_eq!(queue.entries(Side::Bid).len(), MAX_ADL_QUEUE_ENTRIES - 1);
+    }
+
+    #[test]
+    fn claimable_balances_accumulate_until_taken() {
+        let mut balances = ClaimableBalances {
+            market: Pubkey::default(),
+            owner: Pubkey::new_unique(),
+            bump: 0,
+            base_claimable: 0,
+            quote_claimable: 0,
+        };
+        balances.credit(0, 500).unwrap();
+        balances.credit(2, 0).unwrap();
+        assert_eq!(balances.take(), (2, 500));
+        assert_eq!(balances.take(), (0, 0));
+        assert!(balances.credit(u64::MAX, 0).is_ok());
+        assert!(balances.credit(1, 0).is_err());
+    }
+
+    #[test]
+    fn margin_summary_counts_resting_orders_towards_initial_margin_only() {
+        let mut market = perp_market();
+        market.funding_rate = 10 * math::FUNDING_PRECISION as i64;
+        let mut long = position();
+        long.side = Side::Bid;
+        long.size = 2_000_000;
+        long.entry_price = 100_000_000;
+        long.realized_pnl = -1_000_000;
+
+        // $40 equity less an interval of funding at 10 bps on $200
+        let mut summary = MarginSummary::new(20_000_000);
+        summary.add_position(&long, &market, 110_000_000, &OpenOrderTotals::default(), 3_600).unwrap();
+        assert_eq!(summary.equity, 38_800_000);
+        // Unrealized gains can't be withdrawn
+        assert_eq!(summary.withdrawable_equity, 18_800_000);
+        assert_eq!(summary.initial_margin, 22_000_000);
+        assert_eq!(summary.maintenance_margin, 11_000_000);
+
+        // A resting bid below the market is sized at the mark
+        let mut open_orders = OpenOrderTotals::default();
+        open_orders.add(Side::Bid, 1_000_000, 100_000_000).unwrap();
+        let mut summary = MarginSummary::new(20_000_000);
+        summary.add_position(&long, &market, 110_000_000, &open_orders, 3_600).unwrap();
+        assert_eq!(summary.initial_margin, 33_000_000);
+        assert_eq!(summary.maintenance_margin, 11_000_000);
+        assert!(!summary.is_liquidatable());
+    }
+}
@@ -0,0 +1,370 @@
+use anchor_lang::prelude::*;
+
+use crate::{ErrorCode, Side};
+
+// Prices and notionals use 6 decimals
+pub const PRICE_DECIMALS: i32 = 6;
+pub const PRICE_PRECISION: u64 = 1_000_000;
+
+// Ratios such as fees and margin requirements are in basis points
+pub const BPS_DENOMINATOR: u64 = 10_000;
+
+// Funding rates are in bps scaled up so partial intervals accrue exactly.
+// Cumulative funding indexes are quote per unit of size, scaled by
+// PRICE_PRECISION * FUNDING_PRECISION.
+pub const FUNDING_PRECISION: u64 = 1_000_000;
+
+// Narrow an i128 intermediate back to u64
+pub fn to_u64(value: i128) -> Result<u64> {
+    u64::try_from(value).map_err(|_| ErrorCode::MathOverflow.into())
+}
+
+// Narrow an i128 intermediate back to i64
+pub fn to_i64(value: i128) -> Result<i64> {
+    i64::try_from(value).map_err(|_| ErrorCode::MathOverflow.into())
+}
+
+// Quote value of `size` base units at `price`
+pub fn notional(size: u64, price: u64) -> Result<u64> {
+    let value = (size as i128)
+        .checked_mul(price as i128)
+        .ok_or(ErrorCode::MathOverflow)?
+        / PRICE_PRECISION as i128;
+    to_u64(value)
+}
+
+// `bps` basis points of `amount`
+pub fn bps_of(amount: u64, bps: u16) -> Result<u64> {
+    let value = (amount as i128)
+        .checked_mul(bps as i128)
+        .ok_or(ErrorCode::MathOverflow)?
+        / BPS_DENOMINATOR as i128;
+    to_u64(value)
+}
+
+// PnL of closing `size` of a position on `side` opened at `entry_price` at `exit_price`
+pub fn pnl(side: Side, entry_price: u64, exit_price: u64, size: u64) -> Result<i64> {
+    let price_delta = match side {
+        Side::Bid => exit_price as i128 - entry_price as i128,
+        Side::Ask => entry_price as i128 - exit_price as i128,
+    };
+    let value = price_delta
+        .checked_mul(size as i128)
+        .ok_or(ErrorCode::MathOverflow)?
+        / PRICE_PRECISION as i128;
+    to_i64(value)
+}
+
+// Margin plus PnL, floored at zero
+pub fn equity(margin: u64, pnl: i64) -> Result<u64> {
+    let value = (margin as i128)
+        .checked_add(pnl as i128)
+        .ok_or(ErrorCode::MathOverflow)?;
+    to_u64(value.max(0))
+}
+
+// Price at which a position's margin falls to the maintenance requirement:
+// long:  entry * (1 - 1/leverage + mmr)
+// short: entry * (1 + 1/leverage - mmr)
+pub fn liquidation_price(
+    side: Side,
+    entry_price: u64,
+    leverage: u16,
+    maintenance_margin_ratio: u16,
+) -> Result<u64> {
+    require!(leverage > 0, ErrorCode::InvalidParameters);
+
+    let bps = BPS_DENOMINATOR as i128;
+    let leverage = leverage as i128;
+    let maintenance = maintenance_margin_ratio as i128;
+
+    // Ratio scaled by bps * leverage to stay in integers
+    let denominator = bps * leverage;
+    let numerator = match side {
+        Side::Bid => denominator - bps + maintenance * leverage,
+        Side::Ask => denominator + bps - maintenance * leverage,
+    };
+
+    let value = (entry_price as i128)
+        .checked_mul(numerator.max(0))
+        .ok_or(ErrorCode::MathOverflow)?
+        / denominator;
+    to_u64(value)
+}
+
+// Funding paid to (positive) or by (negative) a position on `side` of the
+// given size, for a change in the cumulative long funding index
+pub fn funding_payment(side: Side, size: u64, index_delta: i128) -> Result<i64> {
+    let payment = index_delta
+        .checked_mul(size as i128)
+        .ok_or(ErrorCode::MathOverflow)?
+        / (PRICE_PRECISION as i128 * FUNDING_PRECISION as i128);
+    match side {
+        Side::Bid => to_i64(-payment),
+        Side::Ask => to_i64(payment),
+    }
+}
+
+// Size to close at `price` to bring an account `deficit` below its
+// maintenance requirement back to it. Each unit closed frees its maintenance
+// margin but costs the liquidation fee, so returns u64::MAX when the fee is
+// at least the maintenance ratio and no size restores the account.
+pub fn size_to_restore_margin(
+    deficit: u128,
+    price: u64,
+    maintenance_margin_ratio: u16,
+    liquidation_fee: u16,
+) -> Result<u64> {
+    if maintenance_margin_ratio <= liquidation_fee || price == 0 {
+        return Ok(u64::MAX);
+    }
+
+    // Notional that must be closed, rounded up
+    let freed_bps = (maintenance_margin_ratio - liquidation_fee) as u128;
+    let notional = deficit
+        .checked_mul(BPS_DENOMINATOR as u128)
+        .ok_or(ErrorCode::MathOverflow)?
+        .div_ceil(freed_bps);
+
+    let size = notional
+        .checked_mul(PRICE_PRECISION as u128)
+        .ok_or(ErrorCode::MathOverflow)?
+        .div_ceil(price as u128);
+    Ok(u64::try_from(size).unwrap_or(u64::MAX))
+}
+
+// Price at which closing `size` of a position on `side` leaves its account
+// with zero equity, given the account's `equity` at `oracle_price`. Rounded
+// in the bankrupt account's favour so closing at it leaves no bad debt.
+pub fn bankruptcy_price(side: Side, oracle_price: u64, equity: i128, size: u64) -> Result<u64> {
+    require!(size > 0, ErrorCode::InvalidParameters);
+
+    let shortfall = u128::try_from(-equity).unwrap_or(0);
+    let adjustment = shortfall
+        .checked_mul(PRICE_PRECISION as u128)
+        .ok_or(ErrorCode::MathOverflow)?
+        .div_ceil(size as u128);
+    let adjustment = u64::try_from(adjustment).map_err(|_| ErrorCode::MathOverflow)?;
+
+    // Longs must sell above the oracle price and shorts buy below it
+    match side {
+        Side::Bid => oracle_price.checked_add(adjustment).ok_or(ErrorCode::MathOverflow.into()),
+        Side::Ask => Ok(oracle_price.saturating_sub(adjustment)),
+    }
+}
+
+// Auto-deleveraging rank of a position: its unrealized profit in bps of the
+// entry notional times its leverage. Positions that are not in profit score zero.
+pub fn adl_score(unrealized_pnl: i64, entry_notional: u64, leverage: u16) -> u64 {
+    if unrealized_pnl <= 0 || entry_notional == 0 {
+        return 0;
+    }
+
+    let profit_bps = unrealized_pnl as u128 * BPS_DENOMINATOR as u128 / entry_notional as u128;
+    u64::try_from(profit_bps * leverage as u128).unwrap_or(u64::MAX)
+}
+
+// Time-weighted average over a trailing `window`: `price` stood for `elapsed`
+// seconds and the previous average covers the rest of the window
+pub fn twap(average: u64, price: u64, elapsed: u64, window: u64) -> Result<u64> {
+    require!(window > 0, ErrorCode::InvalidParameters);
+
+    let elapsed = std::cmp::min(elapsed, window) as u128;
+    let window = window as u128;
+    let value = (average as u128 * (window - elapsed) + price as u128 * elapsed) / window;
+    u64::try_from(value).map_err(|_| ErrorCode::MathOverflow.into())
+}
+
+// Premium of the mark over the oracle price, in bps scaled by FUNDING_PRECISION
+pub fn premium_index(mark_price: u64, oracle_price: u64) -> Result<i64> {
+    if oracle_price == 0 {
+        return Ok(0);
+    }
+
+    let value = (mark_price as i128 - oracle_price as i128)
+        .checked_mul(BPS_DENOMINATOR as i128 * FUNDING_PRECISION as i128)
+        .ok_or(ErrorCode::MathOverflow)?
+        / oracle_price as i128;
+    to_i64(value)
+}
+
+// Funding rate per interval for a premium paid off over `funding_period`
+// seconds, scaled by a multiplier in bps and capped at `max_funding_rate` bps
+pub fn funding_rate(
+    premium_index: i64,
+    funding_interval: u64,
+    funding_period: u64,
+    multiplier: u16,
+    max_funding_rate: u16,
+) -> Result<i64> {
+    if funding_period == 0 {
+        return Ok(0);
+    }
+
+    let rate = (premium_index as i128)
+        .checked_mul(funding_interval as i128 * multiplier as i128)
+        .ok_or(ErrorCode::MathOverflow)?
+        / (funding_period as i128 * BPS_DENOMINATOR as i128);
+    let cap = max_funding_rate as i128 * FUNDING_PRECISION as i128;
+    to_i64(rate.clamp(-cap, cap))
+}
+
+// Rescale a value with a decimal `exponent` to PRICE_DECIMALS decimals,
+// truncating any extra precision
+pub fn scale_price(value: u64, exponent: i32) -> Result<u64> {
+    let shift = exponent.checked_add(PRICE_DECIMALS).ok_or(ErrorCode::MathOverflow)?;
+    if shift >= 0 {
+        let factor = 10u64.checked_pow(shift as u32).ok_or(ErrorCode::MathOverflow)?;
+        value.checked_mul(factor).ok_or(ErrorCode::MathOverflow.into())
+    } else {
+        // Divisors too large for a u64 leave nothing at our precision
+        Ok(10u64.checked_pow(shift.unsigned_abs()).map_or(0, |divisor| value / divisor))
+    }
+}
+
+// Distance of `price` from `reference`, in bps of the reference
+pub fn deviation_bps(price: u64, reference: u64) -> Result<u64> {
+    require!(reference > 0, ErrorCode::InvalidParameters);
+
+    let value = (price.abs_diff(reference) as u128)
+        .checked_mul(BPS_DENOMINATOR as u128)
+        .ok_or(ErrorCode::MathOverflow)?
+        / reference as u128;
+    Ok(u64::try_from(value).unwrap_or(u64::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pnl_follows_the_position_side() {
+        // 2 units from $100 to $110
+        assert_eq!(pnl(Side::Bid, 100_000_000, 110_000_000, 2_000_000).unwrap(), 20_000_000);
+        assert_eq!(pnl(Side::Ask, 100_000_000, 110_000_000, 2_000_000).unwrap(), -20_000_000);
+        assert_eq!(pnl(Side::Ask, 110_000_000, 100_000_000, 2_000_000).unwrap(), 20_000_000);
+        // Sub-precision PnL truncates towards zero
+        assert_eq!(pnl(Side::Bid, 100, 101, 1).unwrap(), 0);
+        assert_eq!(pnl(Side::Ask, 100, 101, 1).unwrap(), 0);
+        assert!(pnl(Side::Bid, 0, u64::MAX, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn equity_floors_at_zero() {
+        assert_eq!(equity(100, 30).unwrap(), 130);
+        assert_eq!(equity(100, -30).unwrap(), 70);
+        assert_eq!(equity(100, -130).unwrap(), 0);
+        assert!(equity(u64::MAX, 1).is_err());
+    }
+
+    #[test]
+    fn liquidation_price_at_maintenance() {
+        // 10x with a 5% maintenance ratio
+        assert_eq!(liquidation_price(Side::Bid, 100_000_000, 10, 500).unwrap(), 95_000_000);
+        assert_eq!(liquidation_price(Side::Ask, 100_000_000, 10, 500).unwrap(), 105_000_000);
+        // A fully collateralized long with no maintenance can't be liquidated above zero
+        assert_eq!(liquidation_price(Side::Bid, 100_000_000, 1, 0).unwrap(), 0);
+        assert!(liquidation_price(Side::Bid, 100_000_000, 0, 500).is_err());
+    }
+
+    #[test]
+    fn funding_rate_spreads_the_premium_over_the_period() {
+        // A 1% premium paid off over a day, per hourly interval
+        assert_eq!(funding_rate(100 * FUNDING_PRECISION as i64, 3_600, 86_400, 10_000, 1_000).unwrap(), 4_166_666);
+        assert_eq!(funding_rate(-100 * FUNDING_PRECISION as i64, 3_600, 86_400, 10_000, 1_000).unwrap(), -4_166_666);
+        // Half the multiplier halves the rate
+        assert_eq!(funding_rate(100 * FUNDING_PRECISION as i64, 3_600, 86_400, 5_000, 1_000).unwrap(), 2_083_333);
+        assert_eq!(funding_rate(100 * FUNDING_PRECISION as i64, 3_600, 0, 10_000, 1_000).unwrap(), 0);
+    }
+
+    #[test]
+    fn funding_rate_is_capped() {
+        let cap = 10 * FUNDING_PRECISION as i64;
+        assert_eq!(funding_rate(1_000_000 * FUNDING_PRECISION as i64, 86_400, 86_400, 10_000, 10).unwrap(), cap);
+        assert_eq!(funding_rate(-1_000_000 * FUNDING_PRECISION as i64, 86_400, 86_400, 10_000, 10).unwrap(), -cap);
+    }
+
+    #[test]
+    fn scale_price_rescales_to_six_decimals() {
+        assert_eq!(scale_price(12_345, -2).unwrap(), 123_450_000);
+        assert_eq!(scale_price(7, -6).unwrap(), 7);
+        // Extra precision is truncated
+        assert_eq!(scale_price(123_456_789, -8).unwrap(), 1_234_567);
+        assert_eq!(scale_price(5, -40).unwrap(), 0);
+        assert!(scale_price(1, 20).is_err());
+        assert!(scale_price(u64::MAX, 0).is_err());
+    }
+
+    #[test]
+    fn size_to_restore_margin_nets_out_the_fee() {
+        // Each $1 closed frees 5% maintenance and costs a 1% fee, so a $1
+        // deficit needs $25 closed, a quarter unit at $100
+        assert_eq!(size_to_restore_margin(1_000_000, 100_000_000, 500, 100).unwrap(), 250_000);
+        // Rounded up so the account is always restored
+        assert_eq!(size_to_restore_margin(1, 100_000_000, 500, 100).unwrap(), 1);
+    }
+
+    #[test]
+    fn size_to_restore_margin_edge_cases() {
+        assert_eq!(size_to_restore_margin(1_000_000, 100_000_000, 100, 100).unwrap(), u64::MAX);
+        assert_eq!(size_to_restore_margin(1_000_000, 100_000_000, 100, 200).unwrap(), u64::MAX);
+        assert_eq!(size_to_restore_margin(1_000_000, 0, 500, 100).unwrap(), u64::MAX);
+        // Sizes past u64 saturate, and intermediates past u128 fail
+        assert_eq!(size_to_restore_margin(100_000_000_000_000_000_000, 1, 500, 100).unwrap(), u64::MAX);
+        assert!(size_to_restore_margin(u128::MAX / 10_000, 1, 500, 100).is_err());
+    }
+
+    #[test]
+    fn adl_score_ranks_profit_times_leverage() {
+        // 10% profit at 5x
+        assert_eq!(adl_score(10_000_000, 100_000_000, 5), 5_000);
+        // The same profit ranks higher with more leverage
+        assert!(adl_score(10_000_000, 100_000_000, 10) > adl_score(10_000_000, 100_000_000, 5));
+        // And with more profit on the same notional
+        assert!(adl_score(20_000_000, 100_000_000, 5) > adl_score(10_000_000, 100_000_000, 5));
+    }
+
+    #[test]
+    fn adl_score_is_zero_without_profit() {
+        assert_eq!(adl_score(0, 100_000_000, 5), 0);
+        assert_eq!(adl_score(-10_000_000, 100_000_000, 5), 0);
+        assert_eq!(adl_score(10_000_000, 0, 5), 0);
+    }
+
+    #[test]
+    fn adl_score_saturates() {
+        assert_eq!(adl_score(i64::MAX, 1, u16::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn bankruptcy_price_absorbs_the_shortfall() {
+        // $50 short of zero equity over 10 units moves the price $5 in the
+        // position's favour
+        assert_eq!(bankruptcy_price(Side::Bid, 100_000_000, -50_000_000, 10_000_000).unwrap(), 105_000_000);
+        assert_eq!(bankruptcy_price(Side::Ask, 100_000_000, -50_000_000, 10_000_000).unwrap(), 95_000_000);
+
+        // Closing there leaves no loss behind
+        let pnl_at_bankruptcy = pnl(Side::Bid, 100_000_000, 105_000_000, 10_000_000).unwrap();
+        assert_eq!(pnl_at_bankruptcy, 50_000_000);
+    }
+
+    #[test]
+    fn bankruptcy_price_is_the_oracle_for_solvent_accounts() {
+        assert_eq!(bankruptcy_price(Side::Bid, 100_000_000, 0, 10_000_000).unwrap(), 100_000_000);
+        assert_eq!(bankruptcy_price(Side::Ask, 100_000_000, 25_000_000, 10_000_000).unwrap(), 100_000_000);
+    }
+
+    #[test]
+    fn bankruptcy_price_rounds_for_the_bankrupt_account() {
+        assert_eq!(bankruptcy_price(Side::Bid, 100_000_000, -1, 3_000_000).unwrap(), 100_000_001);
+        assert_eq!(bankruptcy_price(Side::Ask, 100_000_000, -1, 3_000_000).unwrap(), 99_999_999);
+        // Shorts can't be pushed below zero
+        assert_eq!(bankruptcy_price(Side::Ask, 1_000_000, -50_000_000, 1_000_000).unwrap(), 0);
+    }
+
+    #[test]
+    fn bankruptcy_price_rejects_empty_positions_and_overflow() {
+        assert!(bankruptcy_price(Side::Bid, 100_000_000, -1, 0).is_err());
+        assert!(bankruptcy_price(Side::Bid, u64::MAX, -50_000_000, 1).is_err());
+    }
+}
@@ -0,0 +1,1228 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::hash::hashv;
+use bytemuck::Zeroable;
+
+use crate::{math, ErrorCode, Order, SelfTradeBehavior, Side};
+
+// Maximum number of resting orders on each side of the book
+pub const MAX_BOOK_ORDERS: usize = 4096;
+
+// Maximum number of distinct users with resting orders
+pub const MAX_BOOK_USERS: usize = 4096;
+
+// Slots in the per-user table, kept at most half full so probes stay short.
+// Must be a power of two.
+pub const USER_TABLE_SIZE: usize = 2 * MAX_BOOK_USERS;
+
+// Most expired orders read past, or pruned, at the top of one side in one go
+pub const MAX_EXPIRED_WALK: usize = 16;
+
+// Empty handle
+pub const NIL: u32 = u32::MAX;
+
+// Crit bits run from 0 to 127 and only grow down a path, so no path through
+// a side holds more inner nodes than this
+const MAX_TREE_DEPTH: usize = 128;
+
+// Handles with this bit set point at order slots, all others at inner nodes
+const LEAF_BIT: u32 = 1 << 31;
+
+// Zero-copy orderbook. Each side is a critbit tree over order keys with a
+// fixed slab of order slots, so inserts and removals walk at most one path
+// of the tree and an order keeps the same slot handle while it rests.
+// A hash table keyed by user links each user's resting orders and keeps
+// their totals, so per-user lookups don't scan the slabs.
+#[account(zero_copy)]
+pub struct Orderbook {
+    pub market: Pubkey,
+    pub bids: BookSide,
+    pub asks: BookSide,
+    pub user_count: u32,
+    pub padding: [u8; 12],
+    pub users: [UserOrders; USER_TABLE_SIZE],
+}
+
+#[zero_copy]
+pub struct BookSide {
+    pub root: u32,
+    pub order_count: u32,
+    pub free_slot_head: u32,
+    pub free_node_head: u32,
+    // Slots and nodes from these indexes on have never been used. They are
+    // handed out once the free lists run dry, so init doesn't walk the slabs.
+    pub next_unused_slot: u32,
+    pub next_unused_node: u32,
+    pub padding: [u32; 2],
+    pub nodes: [InnerNode; MAX_BOOK_ORDERS - 1],
+    pub slots: [OrderSlot; MAX_BOOK_ORDERS],
+}
+
+#[zero_copy]
+pub struct InnerNode {
+    // First bit, counting from the most significant, where the two subtrees differ
+    pub crit_bit: u32,
+    // Child handles; children[0] doubles as the free list link
+    pub children: [u32; 2],
+    pub padding: u32,
+}
+
+#[zero_copy]
+pub struct OrderSlot {
+    pub key: u128,
+    pub user: Pubkey,
+    pub id: u64,
+    pub client_id: u64,
+    pub price: u64,
+    pub size: u64,
+    pub remaining_size: u64,
+    pub timestamp: u64,
+    // Unix timestamp after which the order can't be filled, zero for none
+    pub expiry: u64,
+    pub next_free: u32,
+    // Neighbouring slots in the owner's list of orders on this side
+    pub prev_user_order: u32,
+    pub next_user_order: u32,
+    pub side: u8,
+    pub time_in_force: u8,
+    pub reduce_only: u8,
+    pub post_only: u8,
+    pub in_use: u8,
+    pub padding: [u8; 7],
+}
+
+// One user's resting orders, an entry in the orderbook's user table.
+// Entries without orders are empty.
+#[zero_copy]
+pub struct UserOrders {
+    pub user: Pubkey,
+    pub bid_size: u64,
+    pub ask_size: u64,
+    pub bid_notional: u64,
+    pub ask_notional: u64,
+    // First slot of the user's list on each side, indexed by Side
+    pub heads: [u32; 2],
+    pub order_count: u32,
+    pub padding: u32,
+}
+
+// Remaining size and notional of one user's resting orders on each side
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpenOrderTotals {
+    pub bid_size: u64,
+    pub ask_size: u64,
+    pub bid_notional: u64,
+    pub ask_notional: u64,
+}
+
+impl OpenOrderTotals {
+    pub fn is_empty(&self) -> bool {
+        self.bid_size == 0 && self.ask_size == 0
+    }
+
+    // Count another order of `size` at `price` on `side`
+    pub fn add(&mut self, side: Side, size: u64, price: u64) -> Result<()> {
+        let notional = math::notional(size, price)?;
+        let (total_size, total_notional) = match side {
+            Side::Bid => (&mut self.bid_size, &mut self.bid_notional),
+            Side::Ask => (&mut self.ask_size, &mut self.ask_notional),
+        };
+        *total_size = total_size.checked_add(size).ok_or(ErrorCode::MathOverflow)?;
+        *total_notional = total_notional.checked_add(notional).ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    // Exposure the orders on `side` add if they fill, priced at their limits
+    // or at `price`, whichever is higher, so orders resting far from the
+    // market can't understate it
+    pub fn exposure(&self, side: Side, price: u64) -> Result<u64> {
+        let (size, notional) = match side {
+            Side::Bid => (self.bid_size, self.bid_notional),
+            Side::Ask => (self.ask_size, self.ask_notional),
+        };
+        Ok(std::cmp::max(notional, math::notional(size, price)?))
+    }
+
+    // Largest net notional a position of `size` on `side` can reach at
+    // `price` if every order on either side fills
+    pub fn worst_case_exposure(&self, side: Side, size: u64, price: u64) -> Result<u128> {
+        let position_notional = math::notional(size, price)? as i128;
+        let signed_position_notional = match side {
+            Side::Bid => position_notional,
+            Side::Ask => -position_notional,
+        };
+
+        Ok(std::cmp::max(
+            (signed_position_notional + self.exposure(Side::Bid, price)? as i128).unsigned_abs(),
+            (signed_position_notional - self.exposure(Side::Ask, price)? as i128).unsigned_abs(),
+        ))
+    }
+}
+
+// A single maker fill produced by the matching engine
+#[derive(Clone, Debug)]
+pub struct Fill {
+    pub maker_order_id: u64,
+    pub maker_client_id: u64,
+    pub maker: Pubkey,
+    pub price: u64,
+    pub size: u64,
+    pub maker_remaining_size: u64,
+}
+
+// Outcome of matching a taker order against the book
+#[derive(Clone, Debug, Default)]
+pub struct MatchResult {
+    pub fills: Vec<Fill>,
+    pub filled_size: u64,
+    // Taker size removed by self-trade prevention without trading
+    pub decremented_size: u64,
+    // Maker orders removed or decremented by self-trade prevention, with the size taken off the book
+    pub cancelled_makers: Vec<(Order, u64)>,
+    // Set when self-trade prevention cancelled the rest of the taker order
+    pub taker_cancelled: bool,
+    // Maker orders found past their expiry and pruned from the book
+    pub expired_makers: Vec<Order>,
+}
+
+// Sort key giving price-time priority: the smallest key on each side is the
+// best price, earliest order. Bid prices are inverted so higher bids sort first.
+pub fn order_key(side: Side, price: u64, order_id: u64) -> u128 {
+    let price_bits = match side {
+        Side::Bid => !price,
+        Side::Ask => price,
+    };
+    ((price_bits as u128) << 64) | order_id as u128
+}
+
+fn is_leaf(handle: u32) -> bool {
+    handle & LEAF_BIT != 0
+}
+
+fn slot_index(handle: u32) -> usize {
+    (handle & !LEAF_BIT) as usize
+}
+
+fn key_bit(key: u128, crit_bit: u32) -> usize {
+    ((key >> (127 - crit_bit)) & 1) as usize
+}
+
+impl OrderSlot {
+    pub fn is_filled(&self) -> bool {
+        self.remaining_size == 0
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expiry != 0 && self.expiry <= now
+    }
+
+    pub fn to_order(&self) -> Order {
+        Order {
+            id: self.id,
+            client_id: self.client_id,
+            user: self.user,
+            side: if self.side == 0 { Side::Bid } else { Side::Ask },
+            price: self.price,
+            size: self.size,
+            remaining_size: self.remaining_size,
+            time_in_force: self.time_in_force,
+            timestamp: self.timestamp,
+            expiry: self.expiry,
+            reduce_only: self.reduce_only != 0,
+            post_only: self.post_only != 0,
+        }
+    }
+}
+
+impl UserOrders {
+    pub fn is_empty(&self) -> bool {
+        self.order_count == 0
+    }
+
+    pub fn totals(&self) -> OpenOrderTotals {
+        OpenOrderTotals {
+            bid_size: self.bid_size,
+            ask_size: self.ask_size,
+            bid_notional: self.bid_notional,
+            ask_notional: self.ask_notional,
+        }
+    }
+
+    // Move an order on `side` at `price` from `old_size` to `new_size` remaining
+    fn update_totals(&mut self, side: Side, price: u64, old_size: u64, new_size: u64) -> Result<()> {
+        let old_notional = math::notional(old_size, price)?;
+        let new_notional = math::notional(new_size, price)?;
+        let (total_size, total_notional) = match side {
+            Side::Bid => (&mut self.bid_size, &mut self.bid_notional),
+            Side::Ask => (&mut self.ask_size, &mut self.ask_notional),
+        };
+        *total_size = total_size
+            .checked_add(new_size)
+            .and_then(|total| total.checked_sub(old_size))
+            .ok_or(ErrorCode::MathOverflow)?;
+        *total_notional = total_notional
+            .checked_add(new_notional)
+            .and_then(|total| total.checked_sub(old_notional))
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+}
+
+impl BookSide {
+    // Reset the side to an empty tree. Slots and nodes come from the unused
+    // ranges until something is freed, so the slabs are left as they are.
+    pub fn init(&mut self) {
+        self.root = NIL;
+        self.order_count = 0;
+        self.free_slot_head = NIL;
+        self.free_node_head = NIL;
+        self.next_unused_slot = 0;
+        self.next_unused_node = 0;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root == NIL
+    }
+
+    pub fn slot(&self, handle: u32) -> &OrderSlot {
+        &self.slots[slot_index(handle)]
+    }
+
+    pub fn slot_mut(&mut self, handle: u32) -> &mut OrderSlot {
+        &mut self.slots[slot_index(handle)]
+    }
+
+    // Handle of the best order on this side
+    pub fn best(&self) -> Option<u32> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let mut handle = self.root;
+        while !is_leaf(handle) {
+            handle = self.nodes[handle as usize].children[0];
+        }
+        Some(handle)
+    }
+
+    // Handle of the best order on this side that hasn't expired at `now`.
+    // Expired orders stay until they are pruned, so walk past them in key
+    // order, but only past MAX_EXPIRED_WALK of them. The order after those
+    // stands in even if it has expired too; its price is no worse than any
+    // live order's behind it.
+    pub fn best_unexpired(&self, now: u64) -> Option<u32> {
+        if self.is_empty() {
+            return None;
+        }
+
+        // Right children still to visit on the way down
+        let mut stack = [NIL; MAX_TREE_DEPTH];
+        let mut depth = 0;
+        let mut handle = self.root;
+        let mut skipped = 0;
+        loop {
+            while !is_leaf(handle) {
+                let node = &self.nodes[handle as usize];
+                stack[depth] = node.children[1];
+                depth += 1;
+                handle = node.children[0];
+            }
+
+            if !self.slot(handle).is_expired(now) || skipped == MAX_EXPIRED_WALK {
+                return Some(handle);
+            }
+            skipped += 1;
+
+            if depth == 0 {
+                return None;
+            }
+            depth -= 1;
+            handle = stack[depth];
+        }
+    }
+
+    // Follow the key's bits down to the leaf sharing the longest prefix with it
+    fn closest_leaf(&self, key: u128) -> u32 {
+        let mut handle = self.root;
+        while !is_leaf(handle) {
+            let node = &self.nodes[handle as usize];
+            handle = node.children[key_bit(key, node.crit_bit)];
+        }
+        handle
+    }
+
+    // Handle of the order with the given key
+    pub fn find(&self, key: u128) -> Option<u32> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let handle = self.closest_leaf(key);
+        if self.slot(handle).key == key {
+            Some(handle)
+        } else {
+            None
+        }
+    }
+
+    // Take a slot off the free list, or the next one never used
+    fn alloc_slot(&mut self) -> Result<usize> {
+        if self.free_slot_head != NIL {
+            let slot_idx = self.free_slot_head as usize;
+            self.free_slot_head = self.slots[slot_idx].next_free;
+            return Ok(slot_idx);
+        }
+
+        require!((self.next_unused_slot as usize) < MAX_BOOK_ORDERS, ErrorCode::MarketFull);
+        self.next_unused_slot += 1;
+        Ok(self.next_unused_slot as usize - 1)
+    }
+
+    // Take an inner node off the free list, or the next one never used. Every
+    // order but the first needs one, so one is always left for a free slot.
+    fn alloc_node(&mut self) -> u32 {
+        if self.free_node_head != NIL {
+            let node_idx = self.free_node_head;
+            self.free_node_head = self.nodes[node_idx as usize].children[0];
+            return node_idx;
+        }
+
+        self.next_unused_node += 1;
+        self.next_unused_node - 1
+    }
+
+    // Insert an order, returning its slot handle
+    pub fn insert(&mut self, order: &Order) -> Result<u32> {
+        let key = order_key(order.side, order.price, order.id);
+        require!(self.find(key).is_none(), ErrorCode::InvalidParameters);
+
+        let slot_idx = self.alloc_slot()?;
+        self.slots[slot_idx] = OrderSlot {
+            key,
+            user: order.user,
+            id: order.id,
+            client_id: order.client_id,
+            price: order.price,
+            size: order.size,
+            remaining_size: order.remaining_size,
+            timestamp: order.timestamp,
+            expiry: order.expiry,
+            next_free: NIL,
+            prev_user_order: NIL,
+            next_user_order: NIL,
+            side: order.side as u8,
+            time_in_force: order.time_in_force,
+            reduce_only: order.reduce_only as u8,
+            post_only: order.post_only as u8,
+            in_use: 1,
+            padding: [0; 7],
+        };
+        self.order_count += 1;
+
+        let leaf = slot_idx as u32 | LEAF_BIT;
+        if self.is_empty() {
+            self.root = leaf;
+            return Ok(leaf);
+        }
+
+        // The new inner node splits on the first bit where the key leaves the tree
+        let closest_key = self.slot(self.closest_leaf(key)).key;
+        let crit_bit = (closest_key ^ key).leading_zeros();
+
+        // Descend to the first subtree that splits below that bit
+        let mut parent = NIL;
+        let mut parent_dir = 0;
+        let mut handle = self.root;
+        while !is_leaf(handle) {
+            let node = &self.nodes[handle as usize];
+            if node.crit_bit > crit_bit {
+                break;
+            }
+            parent = handle;
+            parent_dir = key_bit(key, node.crit_bit);
+            handle = node.children[parent_dir];
+        }
+
+        let node_idx = self.alloc_node();
+
+        let dir = key_bit(key, crit_bit);
+        let mut children = [NIL; 2];
+        children[dir] = leaf;
+        children[1 - dir] = handle;
+        self.nodes[node_idx as usize] = InnerNode {
+            crit_bit,
+            children,
+            padding: 0,
+        };
+
+        if parent == NIL {
+            self.root = node_idx;
+        } else {
+            self.nodes[parent as usize].children[parent_dir] = node_idx;
+        }
+
+        Ok(leaf)
+    }
+
+    // Remove an order by handle and return it
+    pub fn remove(&mut self, leaf: u32) -> Result<Order> {
+        require!(!self.is_empty() && self.slot(leaf).in_use != 0, ErrorCode::OrderNotFound);
+
+        let key = self.slot(leaf).key;
+
+        // Walk down to the leaf, remembering its parent and grandparent
+        let mut grandparent = NIL;
+        let mut grandparent_dir = 0;
+        let mut parent = NIL;
+        let mut parent_dir = 0;
+        let mut handle = self.root;
+        while !is_leaf(handle) {
+            grandparent = parent;
+            grandparent_dir = parent_dir;
+            parent = handle;
+            parent_dir = key_bit(key, self.nodes[handle as usize].crit_bit);
+            handle = self.nodes[handle as usize].children[parent_dir];
+        }
+        require!(handle == leaf, ErrorCode::OrderNotFound);
+
+        if parent == NIL {
+            self.root = NIL;
+        } else {
+            // Replace the parent with the leaf's sibling and free the parent
+            let sibling = self.nodes[parent as usize].children[1 - parent_dir];
+            if grandparent == NIL {
+                self.root = sibling;
+            } else {
+                self.nodes[grandparent as usize].children[grandparent_dir] = sibling;
+            }
+            self.nodes[parent as usize].children = [self.free_node_head, NIL];
+            self.free_node_head = parent;
+        }
+
+        let order = self.slot(leaf).to_order();
+
+        let slot = self.slot_mut(leaf);
+        slot.in_use = 0;
+        slot.next_free = NIL;
+        let slot_idx = slot_index(leaf);
+        self.slots[slot_idx].next_free = self.free_slot_head;
+        self.free_slot_head = slot_idx as u32;
+        self.order_count -= 1;
+
+        Ok(order)
+    }
+}
+
+impl Orderbook {
+    pub const SIZE: usize = std::mem::size_of::<Orderbook>();
+
+    // A zeroed user table is already empty
+    pub fn init(&mut self, market: Pubkey) {
+        self.market = market;
+        self.bids.init();
+        self.asks.init();
+        self.user_count = 0;
+    }
+
+    pub fn side(&self, side: Side) -> &BookSide {
+        match side {
+            Side::Bid => &self.bids,
+            Side::Ask => &self.asks,
+        }
+    }
+
+    pub fn side_mut(&mut self, side: Side) -> &mut BookSide {
+        match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        }
+    }
+
+    // Home index of a user in the user table. The whole key is hashed with
+    // the market's, so users can't grind keys that pile into one probe run.
+    fn user_home(&self, user: &Pubkey) -> usize {
+        let hash = hashv(&[self.market.as_ref(), user.as_ref()]).to_bytes();
+        let mut prefix = [0u8; 8];
+        prefix.copy_from_slice(&hash[..8]);
+        u64::from_le_bytes(prefix) as usize & (USER_TABLE_SIZE - 1)
+    }
+
+    // Index of a user's entry in the user table and whether it was found,
+    // or of the empty entry where it would go. The table is never full, so
+    // the probe always ends.
+    fn user_entry(&self, user: &Pubkey) -> (usize, bool) {
+        let mut idx = self.user_home(user);
+        loop {
+            let entry = &self.users[idx];
+            if entry.is_empty() {
+                return (idx, false);
+            }
+            if entry.user == *user {
+                return (idx, true);
+            }
+            idx = (idx + 1) & (USER_TABLE_SIZE - 1);
+        }
+    }
+
+    // Empty a user's entry, shifting later entries of the same probe run
+    // back so lookups never stop early at the gap
+    fn remove_user_entry(&mut self, mut gap: usize) {
+        let mut idx = gap;
+        loop {
+            idx = (idx + 1) & (USER_TABLE_SIZE - 1);
+            if self.users[idx].is_empty() {
+                break;
+            }
+
+            // An entry stays put if its home lies after the gap, up to and
+            // including its own index
+            let user = self.users[idx].user;
+            let home = self.user_home(&user);
+            let stays = if gap <= idx {
+                gap < home && home <= idx
+            } else {
+                gap < home || home <= idx
+            };
+            if !stays {
+                self.users[gap] = self.users[idx];
+                gap = idx;
+            }
+        }
+
+        self.users[gap] = UserOrders::zeroed();
+        self.user_count -= 1;
+    }
+
+    // A user's resting orders, if they have any
+    pub fn user_orders(&self, user: &Pubkey) -> Option<&UserOrders> {
+        match self.user_entry(user) {
+            (idx, true) => Some(&self.users[idx]),
+            _ => None,
+        }
+    }
+
+    // Remaining size and notional of a user's resting bids and asks
+    pub fn open_orders(&self, user: &Pubkey) -> OpenOrderTotals {
+        self.user_orders(user).map(UserOrders::totals).unwrap_or_default()
+    }
+
+    // Handles of a user's resting orders on one side, newest first
+    pub fn user_order_handles(&self, side: Side, user: &Pubkey) -> Vec<u32> {
+        let mut handles = Vec::new();
+        if let Some(entry) = self.user_orders(user) {
+            let book_side = self.side(side);
+            let mut handle = entry.heads[side as usize];
+            while handle != NIL {
+                handles.push(handle);
+                handle = book_side.slot(handle).next_user_order;
+            }
+        }
+        handles
+    }
+
+    // Get the best bid price among orders live at `now`
+    pub fn best_bid_price(&self, now: u64) -> Option<u64> {
+        self.bids.best_unexpired(now).map(|handle| self.bids.slot(handle).price)
+    }
+
+    // Get the best ask price among orders live at `now`
+    pub fn best_ask_price(&self, now: u64) -> Option<u64> {
+        self.asks.best_unexpired(now).map(|handle| self.asks.slot(handle).price)
+    }
+
+    // Remove up to MAX_EXPIRED_WALK expired orders from the top of one side,
+    // the ones price reads would otherwise walk past. Matching only prunes
+    // the side it takes from, so this keeps the other side clean too.
+    pub fn prune_expired(&mut self, side: Side, now: u64) -> Result<Vec<Order>> {
+        let mut expired = Vec::new();
+        while expired.len() < MAX_EXPIRED_WALK {
+            match self.side(side).best() {
+                Some(handle) if self.side(side).slot(handle).is_expired(now) => {
+                    expired.push(self.remove_order(side, handle)?);
+                },
+                _ => break,
+            }
+        }
+        Ok(expired)
+    }
+
+    // Calculate mid-price from orders live at `now`
+    pub fn mid_price(&self, now: u64) -> Option<u64> {
+        match (self.best_bid_price(now), self.best_ask_price(now)) {
+            (Some(bid), Some(ask)) => Some((bid + ask) / 2),
+            (Some(bid), None) => Some(bid),
+            (None, Some(ask)) => Some(ask),
+            (None, None) => None,
+        }
+    }
+
+    // Fill the empty entry at `idx` with a new user
+    fn add_user_entry(&mut self, idx: usize, user: &Pubkey) {
+        self.users[idx] = UserOrders {
+            user: *user,
+            heads: [NIL; 2],
+            ..UserOrders::zeroed()
+        };
+        self.user_count += 1;
+    }
+
+    // Rest an order on its side of the book, returning its slot handle
+    pub fn insert_order(&mut self, order: &Order) -> Result<u32> {
+        let (idx, found) = self.user_entry(&order.user);
+        require!(found || (self.user_count as usize) < MAX_BOOK_USERS, ErrorCode::MarketFull);
+
+        let handle = self.side_mut(order.side).insert(order)?;
+
+        if !found {
+            self.add_user_entry(idx, &order.user);
+        }
+
+        // Push the order onto the front of the user's list for its side
+        let head = self.users[idx].heads[order.side as usize];
+        let book_side = self.side_mut(order.side);
+        book_side.slot_mut(handle).next_user_order = head;
+        if head != NIL {
+            book_side.slot_mut(head).prev_user_order = handle;
+        }
+
+        let entry = &mut self.users[idx];
+        entry.heads[order.side as usize] = handle;
+        entry.order_count += 1;
+        entry.update_totals(order.side, order.price, 0, order.remaining_size)?;
+
+        Ok(handle)
+    }
+
+    // Take an order off the book, out of its owner's list and out of their totals
+    fn remove_order(&mut self, side: Side, handle: u32) -> Result<Order> {
+        let slot = *self.side(side).slot(handle);
+        require!(slot.in_use != 0, ErrorCode::OrderNotFound);
+        let (idx, found) = self.user_entry(&slot.user);
+        require!(found, ErrorCode::OrderNotFound);
+
+        let book_side = self.side_mut(side);
+        if slot.next_user_order != NIL {
+            book_side.slot_mut(slot.next_user_order).prev_user_order = slot.prev_user_order;
+        }
+        if slot.prev_user_order != NIL {
+            book_side.slot_mut(slot.prev_user_order).next_user_order = slot.next_user_order;
+        } else {
+            self.users[idx].heads[side as usize] = slot.next_user_order;
+        }
+
+        let entry = &mut self.users[idx];
+        entry.update_totals(side, slot.price, slot.remaining_size, 0)?;
+        entry.order_count -= 1;
+        if entry.is_empty() {
+            self.remove_user_entry(idx);
+        }
+
+        self.side_mut(side).remove(handle)
+    }
+
+    // Take `amount` off a resting order, removing it once nothing remains.
+    // Returns the order as it stands afterwards.
+    fn reduce_order(&mut self, side: Side, handle: u32, amount: u64) -> Result<Order> {
+        let slot = *self.side(side).slot(handle);
+        let remaining_size = slot.remaining_size.checked_sub(amount).ok_or(ErrorCode::MathOverflow)?;
+
+        if remaining_size == 0 {
+            let mut order = self.remove_order(side, handle)?;
+            order.remaining_size = 0;
+            return Ok(order);
+        }
+
+        let (idx, found) = self.user_entry(&slot.user);
+        require!(found, ErrorCode::OrderNotFound);
+        self.users[idx].update_totals(side, slot.price, slot.remaining_size, remaining_size)?;
+
+        let slot = self.side_mut(side).slot_mut(handle);
+        slot.remaining_size = remaining_size;
+        Ok(slot.to_order())
+    }
+
+    // Remove a user's order by side, price and id
+    pub fn cancel_order(&mut self, side: Side, price: u64, order_id: u64, user: &Pubkey) -> Result<Order> {
+        let book_side = self.side(side);
+        let handle = book_side
+            .find(order_key(side, price, order_id))
+            .ok_or(ErrorCode::OrderNotFound)?;
+        require!(book_side.slot(handle).user == *user, ErrorCode::OrderNotFound);
+
+        self.remove_order(side, handle)
+    }
+
+    // Remove every resting order belonging to a user
+    pub fn cancel_user_orders(&mut self, user: &Pubkey) -> Result<Vec<Order>> {
+        let mut cancelled = Vec::new();
+
+        for side in [Side::Bid, Side::Ask] {
+            for handle in self.user_order_handles(side, user) {
+                cancelled.push(self.remove_order(side, handle)?);
+            }
+        }
+
+        Ok(cancelled)
+    }
+
+    // Match a taker order against the opposite side of the book in
+    // price-time priority, stopping at `limit_price` if one is given.
+    // Makers expired at `now` are pruned instead of filled.
+    pub fn match_order(
+        &mut self,
+        taker: &Pubkey,
+        side: Side,
+        limit_price: Option<u64>,
+        size: u64,
+        self_trade_behavior: SelfTradeBehavior,
+        now: u64,
+    ) -> Result<MatchResult> {
+        let maker_side = match side {
+            Side::Bid => Side::Ask,
+            Side::Ask => Side::Bid,
+        };
+
+        let mut result = MatchResult::default();
+        let mut remaining = size;
+
+        while remaining > 0 {
+            let Some(handle) = self.side(maker_side).best() else {
+                break;
+            };
+            let maker_order = *self.side(maker_side).slot(handle);
+
+            // Prune expired orders as they reach the top of the book
+            if maker_order.is_expired(now) {
+                result.expired_makers.push(self.remove_order(maker_side, handle)?);
+                continue;
+            }
+
+            // Stop once the book no longer crosses the taker's limit
+            let crosses = match (side, limit_price) {
+                (_, None) => true,
+                (Side::Bid, Some(limit)) => maker_order.price <= limit,
+                (Side::Ask, Some(limit)) => maker_order.price >= limit,
+            };
+            if !crosses {
+                break;
+            }
+
+            // Check for self-trade
+            if maker_order.user == *taker {
+                match self_trade_behavior {
+                    SelfTradeBehavior::CancelTaker => {
+                        return Err(ErrorCode::SelfTradePrevented.into());
+                    },
+                    SelfTradeBehavior::CancelMaker => {
+                        let cancelled = self.remove_order(maker_side, handle)?;
+                        let cancelled_size = cancelled.remaining_size;
+                        result.cancelled_makers.push((cancelled, cancelled_size));
+                        continue;
+                    },
+                    SelfTradeBehavior::CancelBoth => {
+                        let cancelled = self.remove_order(maker_side, handle)?;
+                        let cancelled_size = cancelled.remaining_size;
+                        result.cancelled_makers.push((cancelled, cancelled_size));
+                        result.taker_cancelled = true;
+                        break;
+                    },
+                    SelfTradeBehavior::DecrementTake => {
+                        // Remove the overlapping size from both orders without trading
+                        let overlap = std::cmp::min(maker_order.remaining_size, remaining);
+                        remaining -= overlap;
+                        result.decremented_size += overlap;
+
+                        let cancelled = self.reduce_order(maker_side, handle, overlap)?;
+                        result.cancelled_makers.push((cancelled, overlap));
+                        continue;
+                    },
+                }
+            }
+
+            // Calculate match amount
+            let match_amount = std::cmp::min(maker_order.remaining_size, remaining);
+
+            // Update the maker order, removing it once fully filled
+            let maker_remaining_size = self.reduce_order(maker_side, handle, match_amount)?.remaining_size;
+            remaining -= match_amount;
+            result.filled_size += match_amount;
+
+            result.fills.push(Fill {
+                maker_order_id: maker_order.id,
+                maker_client_id: maker_order.client_id,
+                maker: maker_order.user,
+                price: maker_order.price,
+                size: match_amount,
+                maker_remaining_size,
+            });
+        }
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TIME_IN_FORCE_GOOD_TILL_CANCEL;
+
+    fn empty_book() -> Box<Orderbook> {
+        // Far too large for the stack; an all-zero book is valid before init
+        let mut book: Box<Orderbook> = unsafe { Box::new_zeroed().assume_init() };
+        book.init(Pubkey::new_unique());
+        book
+    }
+
+    fn order(id: u64, user: Pubkey, side: Side, price: u64, size: u64) -> Order {
+        Order {
+            id,
+            client_id: id,
+            user,
+            side,
+            price,
+            size,
+            remaining_size: size,
+            time_in_force: TIME_IN_FORCE_GOOD_TILL_CANCEL,
+            timestamp: 0,
+            expiry: 0,
+            reduce_only: false,
+            post_only: false,
+        }
+    }
+
+    // Remove every order on one side, returning their ids best first
+    fn drain(book: &mut Orderbook, side: Side) -> Vec<u64> {
+        let mut ids = Vec::new();
+        while let Some(handle) = book.side(side).best() {
+            ids.push(book.remove_order(side, handle).unwrap().id);
+        }
+        ids
+    }
+
+    #[test]
+    fn insert_find_and_remove() {
+        let mut book = empty_book();
+        let user = Pubkey::new_unique();
+
+        let handle = book.insert_order(&order(1, user, Side::Bid, 100, 10)).unwrap();
+        book.insert_order(&order(2, user, Side::Bid, 90, 10)).unwrap();
+
+        assert_eq!(book.bids.find(order_key(Side::Bid, 100, 1)), Some(handle));
+        assert_eq!(book.bids.find(order_key(Side::Bid, 100, 3)), None);
+        assert_eq!(book.bids.best(), Some(handle));
+        assert_eq!(book.best_bid_price(0), Some(100));
+        assert!(book.insert_order(&order(1, user, Side::Bid, 100, 10)).is_err());
+
+        // Only the owner can cancel
+        assert!(book.cancel_order(Side::Bid, 100, 1, &Pubkey::new_unique()).is_err());
+
+        let removed = book.cancel_order(Side::Bid, 100, 1, &user).unwrap();
+        assert_eq!(removed.id, 1);
+        assert_eq!(book.bids.find(order_key(Side::Bid, 100, 1)), None);
+        assert_eq!(book.best_bid_price(0), Some(90));
+        assert_eq!(book.bids.order_count, 1);
+        assert!(book.cancel_order(Side::Bid, 100, 1, &user).is_err());
+    }
+
+    #[test]
+    fn bids_sort_by_highest_price_then_time() {
+        let mut book = empty_book();
+        let user = Pubkey::new_unique();
+
+        for (id, price) in [(1, 100), (2, 105), (3, 100), (4, 95), (5, 105)] {
+            book.insert_order(&order(id, user, Side::Bid, price, 1)).unwrap();
+        }
+
+        assert_eq!(drain(&mut book, Side::Bid), vec![2, 5, 1, 3, 4]);
+        assert!(book.bids.is_empty());
+    }
+
+    #[test]
+    fn asks_sort_by_lowest_price_then_time() {
+        let mut book = empty_book();
+        let user = Pubkey::new_unique();
+
+        for (id, price) in [(1, 100), (2, 95), (3, 100), (4, 105), (5, 95)] {
+            book.insert_order(&order(id, user, Side::Ask, price, 1)).unwrap();
+        }
+
+        assert_eq!(drain(&mut book, Side::Ask), vec![2, 5, 1, 3, 4]);
+        assert!(book.asks.is_empty());
+    }
+
+    #[test]
+    fn full_side_reuses_freed_slots() {
+        let mut book = empty_book();
+        let user = Pubkey::new_unique();
+        let next_id = MAX_BOOK_ORDERS as u64;
+
+        for id in 0..next_id {
+            book.insert_order(&order(id, user, Side::Ask, 100 + id, 1)).unwrap();
+        }
+        assert_eq!(book.asks.order_count as usize, MAX_BOOK_ORDERS);
+        assert!(book.insert_order(&order(next_id, user, Side::Ask, 1, 1)).is_err());
+
+        // The freed slot is handed out again and the tree stays ordered
+        let freed = book.asks.find(order_key(Side::Ask, 110, 10)).unwrap();
+        book.cancel_order(Side::Ask, 110, 10, &user).unwrap();
+        let reused = book.insert_order(&order(next_id, user, Side::Ask, 1, 1)).unwrap();
+        assert_eq!(reused, freed);
+        assert_eq!(book.best_ask_price(0), Some(1));
+
+        let ids = drain(&mut book, Side::Ask);
+        assert_eq!(ids.len(), MAX_BOOK_ORDERS);
+        assert_eq!(ids[0], next_id);
+        assert!(ids[1..].windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(book.user_orders(&user).is_none());
+        assert_eq!(book.user_count, 0);
+    }
+
+    #[test]
+    fn tracks_each_users_orders_and_totals() {
+        let mut book = empty_book();
+        let alice = Pubkey::new_unique();
+        let bob = Pubkey::new_unique();
+
+        book.insert_order(&order(1, alice, Side::Bid, 2_000_000, 3_000_000)).unwrap();
+        book.insert_order(&order(2, bob, Side::Bid, 2_000_000, 1_000_000)).unwrap();
+        book.insert_order(&order(3, alice, Side::Ask, 3_000_000, 1_000_000)).unwrap();
+        book.insert_order(&order(4, alice, Side::Bid, 1_000_000, 1_000_000)).unwrap();
+
+        assert_eq!(
+            book.open_orders(&alice),
+            OpenOrderTotals {
+                bid_size: 4_000_000,
+                ask_size: 1_000_000,
+                bid_notional: 7_000_000,
+                ask_notional: 3_000_000,
+            }
+        );
+        assert_eq!(book.user_order_handles(Side::Bid, &alice).len(), 2);
+        assert_eq!(book.user_count, 2);
+
+        // Unlinking from the middle of a list keeps the rest of it
+        book.cancel_order(Side::Bid, 2_000_000, 1, &alice).unwrap();
+        assert_eq!(book.user_order_handles(Side::Bid, &alice).len(), 1);
+        assert_eq!(book.open_orders(&alice).bid_notional, 1_000_000);
+
+        let mut ids: Vec<u64> = book.cancel_user_orders(&alice).unwrap().iter().map(|order| order.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec![3, 4]);
+        assert!(book.open_orders(&alice).is_empty());
+        assert!(book.user_orders(&alice).is_none());
+        assert_eq!(book.open_orders(&bob).bid_size, 1_000_000);
+        assert_eq!(book.user_count, 1);
+    }
+
+    #[test]
+    fn user_table_keeps_colliding_users_reachable() {
+        let mut book = empty_book();
+
+        // Users sharing a home index probe into one run
+        let home = book.user_home(&Pubkey::new_unique());
+        let users: Vec<Pubkey> = (0..4)
+            .map(|_| loop {
+                let user = Pubkey::new_unique();
+                if book.user_home(&user) == home {
+                    break user;
+                }
+            })
+            .collect();
+        for (id, user) in users.iter().enumerate() {
+            book.insert_order(&order(id as u64, *user, Side::Bid, 100, 1)).unwrap();
+        }
+
+        // Emptying an entry early in the run shifts the later ones back
+        book.cancel_user_orders(&users[1]).unwrap();
+        for (n, user) in users.iter().enumerate() {
+            assert_eq!(book.user_orders(user).is_some(), n != 1);
+        }
+        book.cancel_user_orders(&users[0]).unwrap();
+        assert_eq!(book.open_orders(&users[3]).bid_size, 1);
+        assert_eq!(book.user_count, 2);
+    }
+
+    #[test]
+    fn user_homes_hash_the_whole_key() {
+        let book = empty_book();
+
+        // Keys sharing their first bytes don't share a home
+        let homes: Vec<usize> = (0..4u8)
+            .map(|n| {
+                let mut bytes = [n; 32];
+                bytes[..8].copy_from_slice(&7u64.to_le_bytes());
+                book.user_home(&Pubkey::new_from_array(bytes))
+            })
+            .collect();
+        assert!(homes.iter().any(|home| *home != homes[0]));
+
+        // The same key lands elsewhere in another market's book
+        let other_book = empty_book();
+        let users: Vec<Pubkey> = (0..4).map(|_| Pubkey::new_unique()).collect();
+        assert!(users.iter().any(|user| book.user_home(user) != other_book.user_home(user)));
+    }
+
+    #[test]
+    fn matches_in_price_time_priority_up_to_the_limit() {
+        let mut book = empty_book();
+        let maker = Pubkey::new_unique();
+        let taker = Pubkey::new_unique();
+
+        book.insert_order(&order(1, maker, Side::Ask, 101, 5)).unwrap();
+        book.insert_order(&order(2, maker, Side::Ask, 100, 5)).unwrap();
+        book.insert_order(&order(3, maker, Side::Ask, 100, 5)).unwrap();
+        book.insert_order(&order(4, maker, Side::Ask, 102, 5)).unwrap();
+
+        let result = book
+            .match_order(&taker, Side::Bid, Some(101), 12, SelfTradeBehavior::CancelMaker, 0)
+            .unwrap();
+        let fills: Vec<(u64, u64, u64)> = result.fills.iter().map(|fill| (fill.maker_order_id, fill.price, fill.size)).collect();
+        assert_eq!(fills, vec![(2, 100, 5), (3, 100, 5), (1, 101, 2)]);
+        assert_eq!(result.filled_size, 12);
+        assert_eq!(result.fills[2].maker_remaining_size, 3);
+        assert_eq!(book.open_orders(&maker).ask_size, 8);
+
+        // The limit stops matching before the 102 ask
+        let result = book
+            .match_order(&taker, Side::Bid, Some(101), 10, SelfTradeBehavior::CancelMaker, 0)
+            .unwrap();
+        assert_eq!(result.filled_size, 3);
+        assert_eq!(book.best_ask_price(0), Some(102));
+    }
+
+    #[test]
+    fn prunes_expired_makers() {
+        let mut book = empty_book();
+        let maker = Pubkey::new_unique();
+        let mut expiring = order(1, maker, Side::Bid, 100, 5);
+        expiring.expiry = 50;
+        book.insert_order(&expiring).unwrap();
+        book.insert_order(&order(2, maker, Side::Bid, 99, 5)).unwrap();
+
+        let result = book
+            .match_order(&Pubkey::new_unique(), Side::Ask, None, 5, SelfTradeBehavior::CancelMaker, 50)
+            .unwrap();
+        assert_eq!(result.expired_makers.len(), 1);
+        assert_eq!(result.fills[0].maker_order_id, 2);
+        assert!(book.open_orders(&maker).is_empty());
+    }
+
+    #[test]
+    fn prices_skip_expired_orders() {
+        let mut book = empty_book();
+        let maker = Pubkey::new_unique();
+        let mut expiring_bid = order(1, maker, Side::Bid, 110, 5);
+        expiring_bid.expiry = 50;
+        let mut expiring_ask = order(2, maker, Side::Ask, 111, 5);
+        expiring_ask.expiry = 60;
+        book.insert_order(&expiring_bid).unwrap();
+        book.insert_order(&expiring_ask).unwrap();
+        book.insert_order(&order(3, maker, Side::Bid, 100, 5)).unwrap();
+        book.insert_order(&order(4, maker, Side::Ask, 120, 5)).unwrap();
+
+        assert_eq!(book.mid_price(49), Some(110));
+        assert_eq!(book.best_bid_price(50), Some(100));
+        assert_eq!(book.best_ask_price(50), Some(111));
+        assert_eq!(book.mid_price(60), Some(110));
+        assert_eq!(book.asks.best(), book.asks.find(order_key(Side::Ask, 111, 2)));
+    }
+
+    #[test]
+    fn price_reads_walk_past_a_bounded_number_of_expired_orders() {
+        let mut book = empty_book();
+        let maker = Pubkey::new_unique();
+        book.insert_order(&order(0, maker, Side::Bid, 100, 5)).unwrap();
+        for id in 1..=MAX_EXPIRED_WALK as u64 {
+            let mut expiring = order(id, maker, Side::Bid, 100 + id, 5);
+            expiring.expiry = 50;
+            book.insert_order(&expiring).unwrap();
+        }
+        assert_eq!(book.best_bid_price(50), Some(100));
+
+        // One more and the walk stops at an expired order, which bids at
+        // least as much as any live one
+        let mut expiring = order(100, maker, Side::Bid, 200, 5);
+        expiring.expiry = 50;
+        book.insert_order(&expiring).unwrap();
+        assert_eq!(book.best_bid_price(50), Some(101));
+
+        // Pruning clears the top of the side in bounded steps
+        let pruned = book.prune_expired(Side::Bid, 50).unwrap();
+        assert_eq!(pruned.len(), MAX_EXPIRED_WALK);
+        assert_eq!(pruned[0].id, 100);
+        assert_eq!(book.best_bid_price(50), Some(100));
+        assert_eq!(book.prune_expired(Side::Bid, 50).unwrap().len(), 1);
+        assert!(book.prune_expired(Side::Bid, 50).unwrap().is_empty());
+        assert_eq!(book.bids.best(), book.bids.find(order_key(Side::Bid, 100, 0)));
+        assert_eq!(book.open_orders(&maker).bid_size, 5);
+    }
+
+    // A user's own ask of 5 at the top of the book with another user's ask behind it
+    fn self_trade_book() -> (Box<Orderbook>, Pubkey, Pubkey) {
+        let mut book = empty_book();
+        let user = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        book.insert_order(&order(1, user, Side::Ask, 100, 5)).unwrap();
+        book.insert_order(&order(2, other, Side::Ask, 100, 5)).unwrap();
+        (book, user, other)
+    }
+
+    #[test]
+    fn self_trade_cancel_taker() {
+        let (mut book, user, _) = self_trade_book();
+        assert!(book
+            .match_order(&user, Side::Bid, Some(100), 4, SelfTradeBehavior::CancelTaker, 0)
+            .is_err());
+    }
+
+    #[test]
+    fn self_trade_cancel_maker() {
+        let (mut book, user, other) = self_trade_book();
+        let result = book
+            .match_order(&user, Side::Bid, Some(100), 4, SelfTradeBehavior::CancelMaker, 0)
+            .unwrap();
+
+        assert_eq!(result.cancelled_makers.len(), 1);
+        assert_eq!(result.cancelled_makers[0].1, 5);
+        assert_eq!(result.fills.len(), 1);
+        assert_eq!(result.fills[0].maker, other);
+        assert_eq!(result.filled_size, 4);
+        assert!(!result.taker_cancelled);
+        assert!(book.open_orders(&user).is_empty());
+    }
+
+    #[test]
+    fn self_trade_cancel_both() {
+        let (mut book, user, other) = self_trade_book();
+        let result = book
+            .match_order(&user, Side::Bid, Some(100), 4, SelfTradeBehavior::CancelBoth, 0)
+            .unwrap();
+
+        assert_eq!(result.cancelled_makers.len(), 1);
+        assert!(result.fills.is_empty());
+        assert!(result.taker_cancelled);
+        assert!(book.open_orders(&user).is_empty());
+        assert_eq!(book.open_orders(&other).ask_size, 5);
+    }
+
+    #[test]
+    fn self_trade_decrement_take() {
+        let (mut book, user, other) = self_trade_book();
+        let result = book
+            .match_order(&user, Side::Bid, Some(100), 4, SelfTradeBehavior::DecrementTake, 0)
+            .unwrap();
+
+        assert_eq!(result.decremented_size, 4);
+        assert_eq!(result.cancelled_makers[0].0.remaining_size, 1);
+        assert!(result.fills.is_empty());
+        assert_eq!(book.open_orders(&user).ask_size, 1);
+        assert_eq!(book.open_orders(&other).ask_size, 5);
+
+        // Decrementing the rest takes the maker off the book, then the taker fills
+        let result = book
+            .match_order(&user, Side::Bid, Some(100), 3, SelfTradeBehavior::DecrementTake, 0)
+            .unwrap();
+        assert_eq!(result.decremented_size, 1);
+        assert_eq!(result.filled_size, 2);
+        assert!(book.user_orders(&user).is_none());
+    }
+}